@@ -0,0 +1,107 @@
+use async_trait::async_trait;
+use socks5_server::{
+    auth::Auth,
+    proto::handshake::{
+        password::{Error as PasswordError, Request as PasswordRequest, Response as PasswordResponse},
+        Method,
+    },
+};
+use std::{
+    collections::HashMap,
+    fs,
+    io::Result as IoResult,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use tokio::net::TcpStream;
+use tracing::{error, info, warn};
+
+// `Ok(false)` means the client sent credentials that were rejected
+pub type AuthOutput = Result<bool, PasswordError>;
+
+pub struct Anonymous;
+
+#[async_trait]
+impl Auth for Anonymous {
+    type Output = AuthOutput;
+
+    fn as_handshake_method(&self) -> Method {
+        Method::NONE
+    }
+
+    async fn execute(&self, _: &mut TcpStream) -> Self::Output {
+        Ok(true)
+    }
+}
+
+pub struct FileAuth {
+    path: PathBuf,
+    users: RwLock<HashMap<String, String>>,
+}
+
+impl FileAuth {
+    pub fn load(path: PathBuf) -> IoResult<Arc<Self>> {
+        let users = RwLock::new(read_users(&path)?);
+        let auth = Arc::new(FileAuth { path, users });
+        #[cfg(unix)]
+        auth.clone().reload_on_sighup()?;
+        Ok(auth)
+    }
+
+    #[cfg(unix)]
+    fn reload_on_sighup(self: Arc<Self>) -> IoResult<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                match read_users(&self.path) {
+                    Ok(users) => {
+                        info!("reloaded {} socks5 users from {}", users.len(), self.path.display());
+                        *self.users.write().expect("users lock poisoned") = users;
+                    }
+                    Err(err) => error!("failed to reload {}: {err}", self.path.display()),
+                }
+            }
+        });
+        Ok(())
+    }
+}
+
+#[async_trait]
+impl Auth for FileAuth {
+    type Output = AuthOutput;
+
+    fn as_handshake_method(&self) -> Method {
+        Method::PASSWORD
+    }
+
+    async fn execute(&self, stream: &mut TcpStream) -> Self::Output {
+        let req = PasswordRequest::read_from(stream).await?;
+        let accepted = {
+            let users = self.users.read().expect("users lock poisoned");
+            let username = String::from_utf8_lossy(&req.username);
+            users.get(username.as_ref()).is_some_and(|password| password.as_bytes() == req.password)
+        };
+        PasswordResponse::new(accepted).write_to(stream).await?;
+        Ok(accepted)
+    }
+}
+
+// one `user:pass` per line, empty lines and `#` comments are skipped
+fn read_users(path: &Path) -> IoResult<HashMap<String, String>> {
+    let mut users = HashMap::new();
+    for (number, line) in fs::read_to_string(path)?.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        match line.split_once(':') {
+            Some((user, pass)) => {
+                users.insert(user.to_owned(), pass.to_owned());
+            }
+            None => warn!("{}:{}: expected user:pass", path.display(), number + 1),
+        }
+    }
+    Ok(users)
+}
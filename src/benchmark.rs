@@ -0,0 +1,105 @@
+use crate::{desync_hello_phrase, Params};
+use std::{
+    io::Result,
+    time::{Duration, Instant},
+};
+use tokio::{
+    io::{copy, sink, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+
+// chunk written per loop iteration of the throughput test
+const THROUGHPUT_CHUNK: usize = 64 * 1024;
+
+// prints: connections,payload_size,connections_per_sec,bytes_per_sec,mean_desync_us
+pub async fn run(connections: usize, payload_size: usize, params: Params) -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0").await?;
+    let addr = listener.local_addr()?;
+    tokio::spawn(async move {
+        while let Ok((mut stream, _)) = listener.accept().await {
+            tokio::spawn(async move {
+                let _ = copy(&mut stream, &mut sink()).await;
+            });
+        }
+    });
+
+    let hello = synthetic_client_hello("example.com", payload_size);
+    let mut desync_time = Duration::ZERO;
+    let start = Instant::now();
+    for _ in 0..connections {
+        let mut stream = TcpStream::connect(addr).await?;
+        stream.set_nodelay(params.nodelay_upstream)?;
+        let desync_start = Instant::now();
+        desync_hello_phrase(&mut &hello[..], &mut stream, addr.port(), params.clone()).await?;
+        desync_time += desync_start.elapsed();
+    }
+    let elapsed = start.elapsed().as_secs_f64();
+
+    let mean_desync_us = desync_time.as_secs_f64() * 1e6 / connections.max(1) as f64;
+    println!(
+        "{connections},{},{:.2},{:.2},{mean_desync_us:.2}",
+        hello.len(),
+        connections as f64 / elapsed,
+        (connections * hello.len()) as f64 / elapsed
+    );
+    Ok(())
+}
+
+// desyncs a synthetic hello to an upstream that discards its input, then
+// sends zeros for `duration`. prints: bytes,seconds,mbps
+pub async fn throughput(host: &str, port: u16, duration: Duration, params: Params) -> Result<()> {
+    let mut stream = TcpStream::connect((host, port)).await?;
+    stream.set_nodelay(params.nodelay_upstream)?;
+    let hello = synthetic_client_hello(host, 512);
+    let start = Instant::now();
+    desync_hello_phrase(&mut &hello[..], &mut stream, port, params).await?;
+    let mut total = hello.len() as u64;
+    let chunk = [0; THROUGHPUT_CHUNK];
+    while start.elapsed() < duration {
+        stream.write_all(&chunk).await?;
+        total += chunk.len() as u64;
+    }
+    stream.flush().await?;
+    let elapsed = start.elapsed().as_secs_f64();
+    println!("{total},{elapsed:.2},{:.2}", total as f64 * 8.0 / elapsed / 1e6);
+    Ok(())
+}
+
+// tls 1.3 style ClientHello for `sni`, padded up to `size` bytes when possible
+pub fn synthetic_client_hello(sni: &str, size: usize) -> Vec<u8> {
+    let sni = sni.as_bytes();
+    let mut extensions = Vec::new();
+    extensions.extend_from_slice(&[0x00, 0x00]);
+    extensions.extend_from_slice(&(sni.len() as u16 + 5).to_be_bytes());
+    extensions.extend_from_slice(&(sni.len() as u16 + 3).to_be_bytes());
+    extensions.push(0x00);
+    extensions.extend_from_slice(&(sni.len() as u16).to_be_bytes());
+    extensions.extend_from_slice(sni);
+
+    let mut body = vec![0x03, 0x03];
+    body.extend_from_slice(&[0x5a; 32]);
+    body.push(32);
+    body.extend_from_slice(&[0xa5; 32]);
+    body.extend_from_slice(&[0x00, 0x02, 0x13, 0x01]);
+    body.extend_from_slice(&[0x01, 0x00]);
+
+    // record header + handshake header + extensions length + padding extension header
+    let used = 5 + 4 + body.len() + 2 + extensions.len() + 4;
+    let padding = size.saturating_sub(used).min(u16::MAX as usize - extensions.len() - 4);
+    if size > used {
+        extensions.extend_from_slice(&[0x00, 0x15]);
+        extensions.extend_from_slice(&(padding as u16).to_be_bytes());
+        extensions.resize(extensions.len() + padding, 0);
+    }
+    body.extend_from_slice(&(extensions.len() as u16).to_be_bytes());
+    body.append(&mut extensions);
+
+    let mut handshake = vec![0x01];
+    handshake.extend_from_slice(&(body.len() as u32).to_be_bytes()[1..]);
+    handshake.append(&mut body);
+
+    let mut record = vec![0x16, 0x03, 0x01];
+    record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+    record.append(&mut handshake);
+    record
+}
@@ -0,0 +1,818 @@
+use crate::{happy_eyeballs::IpPreference, platform, proxy_protocol::ProxyProtocol, parse_hostname, parse_http_trailer, strategy::parse_strategy, parse_host_rewrite, parse_range, parse_split_composite, Method, Params, Parity, Part, TlsrecStrategy, DESYNC_PORTS};
+use clap::{builder::{BoolishValueParser, TypedValueParser}, ArgMatches};
+use std::{collections::HashSet, env, fs, fmt::Display, net::IpAddr, path::PathBuf, str::FromStr, time::Duration};
+
+pub struct Config {
+    pub ip: String,
+    pub port: u16,
+    pub log_format: String,
+    pub log_connections_only: bool,
+    pub socks5_auth_file: Option<PathBuf>,
+    pub sni_list_file: Option<PathBuf>,
+    pub status_socket: Option<PathBuf>,
+    pub wire_log_file: Option<PathBuf>,
+    pub flow_ttl: Duration,
+    pub connection_pool: usize,
+    pub max_connections_per_host: Option<usize>,
+    // set with --no-socks
+    pub upstream: Option<(String, u16)>,
+    pub params: Params,
+}
+
+// settings are applied in increasing precedence: defaults, RUST_DPI_* env vars, cli args
+pub struct ConfigBuilder {
+    ip: String,
+    port: u16,
+    disorder: Option<usize>,
+    split: Option<usize>,
+    split_method: Option<String>,
+    split_at: Option<String>,
+    split_count: Option<usize>,
+    split_parity: Option<String>,
+    segment_count_limit: usize,
+    split_tls_extensions: bool,
+    split_http_headers: bool,
+    split_ipv6: bool,
+    split_on_retransmit: bool,
+    split_before_sni: bool,
+    strategy_file: Option<PathBuf>,
+    methods: Vec<Method>,
+    split_composite: Vec<Method>,
+    oob: Option<usize>,
+    oob_window: Option<usize>,
+    oob_before_sni: bool,
+    oob_data_size: usize,
+    disable_oob_fallback: bool,
+    prefer_split_over_disorder: bool,
+    http_url_split: Option<usize>,
+    tlsrec: Option<usize>,
+    tlsrec_random: Option<(usize, usize)>,
+    tlsrec_after_random_byte: bool,
+    tlsrec_min_size: usize,
+    rewrite_host: Option<(String, String)>,
+    http_remove_headers: Vec<String>,
+    http_uri_encode: Option<String>,
+    http_inject_trailer: Option<(String, String)>,
+    http_version_downgrade: bool,
+    upstream_sni: Option<String>,
+    fake_sni_length: Option<u8>,
+    nodelay_upstream: bool,
+    nodelay_client: bool,
+    write_buf_size: Option<usize>,
+    tcp_cork: bool,
+    half_duplex: bool,
+    keep_alive_upstream_secs: Option<u64>,
+    log_format: String,
+    log_connections_only: bool,
+    trace_http_host: bool,
+    upstream_timeout_ms: Option<u64>,
+    tls_hello_max_wait_ms: Option<u64>,
+    max_read_retries: u32,
+    skip_desync_on_resumption: bool,
+    skip_desync_on_ech: bool,
+    skip_ports: HashSet<u16>,
+    auto_detect_port: bool,
+    prefer_ipv4: bool,
+    prefer_ipv6: bool,
+    upstream_interface_ip: Option<IpAddr>,
+    tcp_window_scale: Option<u8>,
+    tcp_mss: Option<u32>,
+    check_tcp_timestamps: bool,
+    proxy_protocol_v1: bool,
+    proxy_protocol_v2: bool,
+    socks5_auth_file: Option<PathBuf>,
+    sni_list_file: Option<PathBuf>,
+    status_socket: Option<PathBuf>,
+    wire_log_file: Option<PathBuf>,
+    flow_ttl_secs: u64,
+    connection_pool: usize,
+    max_connections_per_host: Option<usize>,
+    no_socks: bool,
+    upstream_host: Option<String>,
+    upstream_port: Option<u16>,
+}
+
+impl Default for ConfigBuilder {
+    fn default() -> Self {
+        ConfigBuilder {
+            ip: "0.0.0.0".to_owned(),
+            port: 1080,
+            disorder: None,
+            split: None,
+            split_method: None,
+            split_at: None,
+            split_count: None,
+            split_parity: None,
+            segment_count_limit: 50,
+            split_tls_extensions: false,
+            split_http_headers: false,
+            split_ipv6: false,
+            split_on_retransmit: false,
+            split_before_sni: false,
+            strategy_file: None,
+            methods: Vec::new(),
+            split_composite: Vec::new(),
+            oob: None,
+            oob_window: None,
+            oob_before_sni: false,
+            oob_data_size: 1,
+            disable_oob_fallback: false,
+            prefer_split_over_disorder: false,
+            http_url_split: None,
+            tlsrec: None,
+            tlsrec_random: None,
+            tlsrec_after_random_byte: false,
+            tlsrec_min_size: 20,
+            rewrite_host: None,
+            http_remove_headers: Vec::new(),
+            http_uri_encode: None,
+            http_inject_trailer: None,
+            http_version_downgrade: false,
+            upstream_sni: None,
+            fake_sni_length: None,
+            nodelay_upstream: true,
+            nodelay_client: false,
+            write_buf_size: None,
+            tcp_cork: false,
+            half_duplex: false,
+            keep_alive_upstream_secs: None,
+            log_format: "text".to_owned(),
+            log_connections_only: false,
+            trace_http_host: false,
+            upstream_timeout_ms: None,
+            tls_hello_max_wait_ms: None,
+            max_read_retries: 3,
+            skip_desync_on_resumption: false,
+            skip_desync_on_ech: false,
+            skip_ports: HashSet::new(),
+            auto_detect_port: false,
+            prefer_ipv4: false,
+            prefer_ipv6: false,
+            upstream_interface_ip: None,
+            tcp_window_scale: None,
+            tcp_mss: None,
+            check_tcp_timestamps: false,
+            proxy_protocol_v1: false,
+            proxy_protocol_v2: false,
+            socks5_auth_file: None,
+            sni_list_file: None,
+            status_socket: None,
+            wire_log_file: None,
+            flow_ttl_secs: 60,
+            connection_pool: 0,
+            max_connections_per_host: None,
+            no_socks: false,
+            upstream_host: None,
+            upstream_port: None,
+        }
+    }
+}
+
+impl ConfigBuilder {
+    pub fn apply_env(mut self) -> Result<Self, String> {
+        set(&mut self.ip, env_var("RUST_DPI_IP")?);
+        set(&mut self.port, env_var("RUST_DPI_PORT")?);
+        set_opt(&mut self.disorder, env_var("RUST_DPI_DISORDER")?);
+        set_opt(&mut self.split, env_var("RUST_DPI_SPLIT")?);
+        set_opt(&mut self.split_method, env_var("RUST_DPI_SPLIT_METHOD")?);
+        set_opt(&mut self.split_at, env_var("RUST_DPI_SPLIT_AT")?);
+        set_opt(&mut self.split_count, env_var("RUST_DPI_SPLIT_COUNT")?);
+        set_opt(&mut self.split_parity, env_var("RUST_DPI_SPLIT_PARITY")?);
+        set(&mut self.segment_count_limit, env_var("RUST_DPI_SEGMENT_COUNT_LIMIT")?);
+        set(&mut self.split_tls_extensions, env_flag("RUST_DPI_SPLIT_TLS_EXTENSIONS")?);
+        set(&mut self.split_http_headers, env_flag("RUST_DPI_SPLIT_HTTP_HEADERS")?);
+        set(&mut self.split_ipv6, env_flag("RUST_DPI_SPLIT_IPV6")?);
+        set(&mut self.split_on_retransmit, env_flag("RUST_DPI_SPLIT_ON_RETRANSMIT")?);
+        set(&mut self.split_before_sni, env_flag("RUST_DPI_SPLIT_BEFORE_SNI")?);
+        set_opt(&mut self.strategy_file, env_var("RUST_DPI_STRATEGY_FILE")?);
+        set_opt(&mut self.oob, env_var("RUST_DPI_OOB")?);
+        set_opt(&mut self.oob_window, env_var("RUST_DPI_OOB_WINDOW")?);
+        set(&mut self.oob_before_sni, env_flag("RUST_DPI_OOB_BEFORE_SNI")?);
+        set(&mut self.oob_data_size, env_var("RUST_DPI_OOB_DATA_SIZE")?);
+        set(&mut self.disable_oob_fallback, env_flag("RUST_DPI_DISABLE_OOB_FALLBACK")?);
+        set(&mut self.prefer_split_over_disorder, env_flag("RUST_DPI_PREFER_SPLIT_OVER_DISORDER")?);
+        set_opt(&mut self.http_url_split, env_var("RUST_DPI_HTTP_URL_SPLIT")?);
+        // comma separated method specs, e.g. `split:40,disorder:1:sni`
+        if let Ok(value) = env::var("RUST_DPI_METHODS") {
+            self.methods = value.split(',')
+                .map(|spec| Method::try_from(spec.trim()))
+                .collect::<Result<_, _>>()
+                .map_err(|e| format!("RUST_DPI_METHODS: {e}"))?;
+        }
+        if let Ok(value) = env::var("RUST_DPI_SPLIT_COMPOSITE") {
+            self.split_composite = parse_split_composite(&value).map_err(|e| format!("RUST_DPI_SPLIT_COMPOSITE: {e}"))?;
+        }
+        set_opt(&mut self.tlsrec, env_var("RUST_DPI_TLSREC")?);
+        if let Ok(value) = env::var("RUST_DPI_TLSREC_RANDOM") {
+            self.tlsrec_random = Some(parse_range(&value).map_err(|e| format!("RUST_DPI_TLSREC_RANDOM: {e}"))?);
+        }
+        set(&mut self.tlsrec_after_random_byte, env_flag("RUST_DPI_TLSREC_AFTER_RANDOM_BYTE")?);
+        set(&mut self.tlsrec_min_size, env_var("RUST_DPI_TLSREC_MIN_SIZE")?);
+        if let Ok(value) = env::var("RUST_DPI_REWRITE_HOST") {
+            self.rewrite_host = Some(parse_host_rewrite(&value).map_err(|e| format!("RUST_DPI_REWRITE_HOST: {e}"))?);
+        }
+        // comma separated header names, e.g. `User-Agent,X-Forwarded-For`
+        if let Ok(value) = env::var("RUST_DPI_HTTP_REMOVE_HEADER") {
+            self.http_remove_headers = value.split(',')
+                .map(str::trim)
+                .filter(|name| !name.is_empty())
+                .map(str::to_owned)
+                .collect();
+        }
+        set_opt(&mut self.http_uri_encode, env_var("RUST_DPI_HTTP_URI_ENCODE")?);
+        if let Ok(value) = env::var("RUST_DPI_HTTP_INJECT_TRAILER") {
+            self.http_inject_trailer = Some(parse_http_trailer(&value).map_err(|e| format!("RUST_DPI_HTTP_INJECT_TRAILER: {e}"))?);
+        }
+        set(&mut self.http_version_downgrade, env_flag("RUST_DPI_HTTP_VERSION_DOWNGRADE")?);
+        if let Ok(value) = env::var("RUST_DPI_UPSTREAM_SNI") {
+            self.upstream_sni = Some(parse_hostname(&value).map_err(|e| format!("RUST_DPI_UPSTREAM_SNI: {e}"))?);
+        }
+        set_opt(&mut self.fake_sni_length, env_var("RUST_DPI_FAKE_SNI_LENGTH")?);
+        set(&mut self.nodelay_upstream, env_flag("RUST_DPI_TCP_NODELAY_UPSTREAM")?);
+        set(&mut self.nodelay_client, env_flag("RUST_DPI_TCP_NODELAY_CLIENT")?);
+        set_opt(&mut self.write_buf_size, env_var("RUST_DPI_WRITE_BUF_SIZE")?);
+        set(&mut self.tcp_cork, env_flag("RUST_DPI_TCP_CORK")?);
+        set(&mut self.half_duplex, env_flag("RUST_DPI_HALF_DUPLEX")?);
+        set_opt(&mut self.keep_alive_upstream_secs, env_var("RUST_DPI_KEEP_ALIVE_UPSTREAM")?);
+        set(&mut self.log_format, env_var("RUST_DPI_LOG_FORMAT")?);
+        set(&mut self.log_connections_only, env_flag("RUST_DPI_LOG_CONNECTIONS_ONLY")?);
+        set(&mut self.trace_http_host, env_flag("RUST_DPI_TRACE_HTTP_HOST")?);
+        set_opt(&mut self.upstream_timeout_ms, env_var("RUST_DPI_UPSTREAM_TIMEOUT_MS")?);
+        set_opt(&mut self.tls_hello_max_wait_ms, env_var("RUST_DPI_TLS_CLIENT_HELLO_MAX_WAIT")?);
+        set(&mut self.max_read_retries, env_var("RUST_DPI_MAX_READ_RETRIES")?);
+        set(&mut self.skip_desync_on_resumption, env_flag("RUST_DPI_SKIP_DESYNC_ON_RESUMPTION")?);
+        set(&mut self.skip_desync_on_ech, env_flag("RUST_DPI_SKIP_DESYNC_ON_ECH")?);
+        // comma separated, e.g. `25,8443`
+        if let Ok(value) = env::var("RUST_DPI_NO_DESYNC_PORT") {
+            self.skip_ports = value.split(',')
+                .map(|port| port.trim().parse())
+                .collect::<Result<_, _>>()
+                .map_err(|e| format!("RUST_DPI_NO_DESYNC_PORT: {e}"))?;
+        }
+        set(&mut self.auto_detect_port, env_flag("RUST_DPI_AUTO_DETECT_PORT")?);
+        set(&mut self.prefer_ipv4, env_flag("RUST_DPI_PREFER_IPV4")?);
+        set(&mut self.prefer_ipv6, env_flag("RUST_DPI_PREFER_IPV6")?);
+        set_opt(&mut self.upstream_interface_ip, env_var("RUST_DPI_UPSTREAM_INTERFACE_IP")?);
+        set_opt(&mut self.tcp_window_scale, env_var("RUST_DPI_TCP_WINDOW_SCALE")?);
+        set_opt(&mut self.tcp_mss, env_var("RUST_DPI_TCP_MSS")?);
+        set(&mut self.check_tcp_timestamps, env_flag("RUST_DPI_CHECK_TCP_TIMESTAMPS")?);
+        set(&mut self.proxy_protocol_v1, env_flag("RUST_DPI_PROXY_PROTOCOL_V1")?);
+        set(&mut self.proxy_protocol_v2, env_flag("RUST_DPI_PROXY_PROTOCOL_V2")?);
+        set_opt(&mut self.socks5_auth_file, env_var("RUST_DPI_SOCKS5_AUTH_FILE")?);
+        set_opt(&mut self.sni_list_file, env_var("RUST_DPI_SNI_LIST_FILE")?);
+        set_opt(&mut self.status_socket, env_var("RUST_DPI_STATUS_SOCKET")?);
+        set_opt(&mut self.wire_log_file, env_var("RUST_DPI_WIRE_LOG_FILE")?);
+        set(&mut self.flow_ttl_secs, env_var("RUST_DPI_FLOW_TTL")?);
+        set(&mut self.connection_pool, env_var("RUST_DPI_CONNECTION_POOL")?);
+        set_opt(&mut self.max_connections_per_host, env_var("RUST_DPI_MAX_UPSTREAM_CONNECTIONS_PER_HOST")?);
+        set(&mut self.no_socks, env_flag("RUST_DPI_NO_SOCKS")?);
+        set_opt(&mut self.upstream_host, env_var("RUST_DPI_UPSTREAM_HOST")?);
+        set_opt(&mut self.upstream_port, env_var("RUST_DPI_UPSTREAM_PORT")?);
+        Ok(self)
+    }
+
+    pub fn apply_matches(mut self, matches: &ArgMatches) -> Self {
+        set(&mut self.ip, matches.get_one::<String>("ip").cloned());
+        set(&mut self.port, matches.get_one::<u16>("port").copied());
+        set_opt(&mut self.disorder, matches.get_one::<usize>("disorder").copied());
+        set_opt(&mut self.split, matches.get_one::<usize>("split").copied());
+        set_opt(&mut self.split_method, matches.get_one::<String>("split-method").cloned());
+        set_opt(&mut self.split_at, matches.get_one::<String>("split-at").cloned());
+        set_opt(&mut self.split_count, matches.get_one::<usize>("split-count").copied());
+        set_opt(&mut self.split_parity, matches.get_one::<String>("split-parity").cloned());
+        set(&mut self.segment_count_limit, matches.get_one::<usize>("segment-count-limit").copied());
+        self.split_tls_extensions |= matches.get_flag("split-tls-extensions");
+        self.split_http_headers |= matches.get_flag("split-http-headers");
+        self.split_ipv6 |= matches.get_flag("split-ipv6");
+        self.split_on_retransmit |= matches.get_flag("split-on-retransmit");
+        self.split_before_sni |= matches.get_flag("split-before-sni");
+        set_opt(&mut self.strategy_file, matches.get_one::<PathBuf>("strategy-file").cloned());
+        set_opt(&mut self.oob, matches.get_one::<usize>("oob").copied());
+        set_opt(&mut self.oob_window, matches.get_one::<usize>("oob-window").copied());
+        self.oob_before_sni |= matches.get_flag("oob-before-sni");
+        set(&mut self.oob_data_size, matches.get_one::<usize>("oob-data-size").copied());
+        self.disable_oob_fallback |= matches.get_flag("disable-oob-fallback");
+        self.prefer_split_over_disorder |= matches.get_flag("prefer-split-over-disorder");
+        set_opt(&mut self.http_url_split, matches.get_one::<usize>("http-url-split").copied());
+        if let Some(methods) = matches.get_many::<Method>("method") {
+            self.methods = methods.cloned().collect();
+        }
+        if let Some(methods) = matches.get_one::<Vec<Method>>("split-composite") {
+            self.split_composite = methods.clone();
+        }
+        set_opt(&mut self.tlsrec, matches.get_one::<usize>("tlsrec").copied());
+        set_opt(&mut self.tlsrec_random, matches.get_one::<(usize, usize)>("tlsrec-random").copied());
+        self.tlsrec_after_random_byte |= matches.get_flag("tlsrec-after-random-byte");
+        set(&mut self.tlsrec_min_size, matches.get_one::<usize>("tlsrec-min-size").copied());
+        set_opt(&mut self.rewrite_host, matches.get_one::<(String, String)>("rewrite-host").cloned());
+        if let Some(names) = matches.get_many::<String>("http-remove-header") {
+            self.http_remove_headers = names.cloned().collect();
+        }
+        set_opt(&mut self.http_uri_encode, matches.get_one::<String>("http-uri-encode").cloned());
+        set_opt(&mut self.http_inject_trailer, matches.get_one::<(String, String)>("http-inject-trailer").cloned());
+        self.http_version_downgrade |= matches.get_flag("http-version-downgrade");
+        set_opt(&mut self.upstream_sni, matches.get_one::<String>("upstream-sni").cloned());
+        set_opt(&mut self.fake_sni_length, matches.get_one::<u8>("fake-sni-length").copied());
+        set(&mut self.nodelay_upstream, matches.get_one::<bool>("tcp-nodelay-upstream").copied());
+        set(&mut self.nodelay_client, matches.get_one::<bool>("tcp-nodelay-client").copied());
+        set_opt(&mut self.write_buf_size, matches.get_one::<usize>("write-buf-size").copied());
+        self.tcp_cork |= matches.get_flag("tcp-cork");
+        self.half_duplex |= matches.get_flag("half-duplex");
+        set_opt(&mut self.keep_alive_upstream_secs, matches.get_one::<u64>("keep-alive-upstream").copied());
+        set(&mut self.log_format, matches.get_one::<String>("log-format").cloned());
+        self.log_connections_only |= matches.get_flag("log-connections-only");
+        self.trace_http_host |= matches.get_flag("trace-http-host");
+        set_opt(&mut self.upstream_timeout_ms, matches.get_one::<u64>("upstream-timeout-ms").copied());
+        set_opt(&mut self.tls_hello_max_wait_ms, matches.get_one::<u64>("tls-client-hello-max-wait").copied());
+        set(&mut self.max_read_retries, matches.get_one::<u32>("max-read-retries").copied());
+        self.skip_desync_on_resumption |= matches.get_flag("skip-desync-on-resumption");
+        self.skip_desync_on_ech |= matches.get_flag("skip-desync-on-ech");
+        if let Some(ports) = matches.get_many::<u16>("no-desync-port") {
+            self.skip_ports = ports.copied().collect();
+        }
+        self.auto_detect_port |= matches.get_flag("auto-detect-port");
+        // a preference on the command line replaces one from the environment
+        if matches.get_flag("prefer-ipv4") || matches.get_flag("prefer-ipv6") {
+            self.prefer_ipv4 = matches.get_flag("prefer-ipv4");
+            self.prefer_ipv6 = matches.get_flag("prefer-ipv6");
+        }
+        set_opt(&mut self.upstream_interface_ip, matches.get_one::<IpAddr>("upstream-interface-ip").copied());
+        set_opt(&mut self.tcp_window_scale, matches.get_one::<u8>("tcp-window-scale").copied());
+        set_opt(&mut self.tcp_mss, matches.get_one::<u32>("tcp-mss").copied());
+        self.check_tcp_timestamps |= matches.get_flag("check-tcp-timestamps");
+        // a version on the command line replaces one from the environment
+        if matches.get_flag("proxy-protocol-v1") || matches.get_flag("proxy-protocol-v2") {
+            self.proxy_protocol_v1 = matches.get_flag("proxy-protocol-v1");
+            self.proxy_protocol_v2 = matches.get_flag("proxy-protocol-v2");
+        }
+        set_opt(&mut self.socks5_auth_file, matches.get_one::<PathBuf>("socks5-auth-file").cloned());
+        set_opt(&mut self.sni_list_file, matches.get_one::<PathBuf>("sni-list-file").cloned());
+        set_opt(&mut self.status_socket, matches.get_one::<PathBuf>("status-socket").cloned());
+        set_opt(&mut self.wire_log_file, matches.get_one::<PathBuf>("wire-log-file").cloned());
+        set(&mut self.flow_ttl_secs, matches.get_one::<u64>("flow-ttl").copied());
+        set(&mut self.connection_pool, matches.get_one::<usize>("connection-pool").copied());
+        set_opt(&mut self.max_connections_per_host, matches.get_one::<usize>("max-upstream-connections-per-host").copied());
+        self.no_socks |= matches.get_flag("no-socks");
+        set_opt(&mut self.upstream_host, matches.get_one::<String>("upstream-host").cloned());
+        set_opt(&mut self.upstream_port, matches.get_one::<u16>("upstream-port").copied());
+        self
+    }
+
+    pub fn build(self) -> Result<Config, String> {
+        if !["text", "json"].contains(&self.log_format.as_str()) {
+            return Err(format!("unknown log format `{}`", self.log_format));
+        }
+        let position_parity = match self.split_parity.as_deref() {
+            None => None,
+            Some("even") => Some(Parity::Even),
+            Some("odd") => Some(Parity::Odd),
+            Some(other) => return Err(format!("unknown split parity `{other}`"))
+        };
+        let ip_preference = match (self.prefer_ipv4, self.prefer_ipv6) {
+            (false, false) => IpPreference::None,
+            (true, false) => IpPreference::PreferV4,
+            (false, true) => IpPreference::PreferV6,
+            (true, true) => return Err("prefer-ipv4 and prefer-ipv6 are mutually exclusive".to_owned())
+        };
+        if self.tcp_window_scale.is_some_and(|scale| scale > 14) {
+            return Err("tcp window scale must be between 0 and 14".to_owned());
+        }
+        if self.tcp_mss.is_some_and(|mss| !(88..=65535).contains(&mss)) {
+            return Err("tcp mss must be between 88 and 65535".to_owned());
+        }
+        let proxy_protocol = match (self.proxy_protocol_v1, self.proxy_protocol_v2) {
+            (false, false) => None,
+            (true, false) => Some(ProxyProtocol::V1),
+            (false, true) => Some(ProxyProtocol::V2),
+            (true, true) => return Err("proxy-protocol-v1 and proxy-protocol-v2 are mutually exclusive".to_owned())
+        };
+        let strategy = match &self.strategy_file {
+            Some(path) => {
+                let source = fs::read_to_string(path).map_err(|e| format!("{}: {e}", path.display()))?;
+                parse_strategy(&source).map_err(|e| format!("{}: {e}", path.display()))?
+            }
+            None => Vec::new()
+        };
+        let upstream = match (self.no_socks, self.upstream_host, self.upstream_port) {
+            (false, _, _) => None,
+            (true, Some(host), Some(port)) => Some((host, port)),
+            (true, _, _) => return Err("--no-socks needs --upstream-host and --upstream-port".to_owned())
+        };
+        if self.tcp_cork && !platform::SUPPORTS_CORK {
+            return Err("tcp-cork needs TCP_CORK, which this platform doesn't have".to_owned());
+        }
+        if self.check_tcp_timestamps {
+            match platform::tcp_timestamps_enabled() {
+                Some(false) => {}
+                Some(true) => return Err("tcp timestamps are on and can't be turned off per socket, set net.ipv4.tcp_timestamps=0".to_owned()),
+                None => return Err("check-tcp-timestamps can't read the tcp timestamps setting on this platform".to_owned())
+            }
+        }
+        if self.http_uri_encode.as_ref().is_some_and(|keyword| keyword.is_empty() || keyword.contains(char::is_whitespace)) {
+            return Err("http-uri-encode needs a keyword without whitespace".to_owned());
+        }
+        if self.segment_count_limit == 0 {
+            return Err("segment count limit must be at least 1".to_owned());
+        }
+        if self.oob_data_size == 0 {
+            return Err("oob data size must be at least 1".to_owned());
+        }
+        if self.max_connections_per_host == Some(0) {
+            return Err("max upstream connections per host must be at least 1".to_owned());
+        }
+        if self.keep_alive_upstream_secs == Some(0) {
+            return Err("upstream keepalive must be at least 1 second".to_owned());
+        }
+        if self.flow_ttl_secs == 0 {
+            return Err("flow ttl must be at least 1 second".to_owned());
+        }
+
+        let disorder = self.disorder
+            .map(|pos| Method::Disorder(Part::absolute(pos)));
+        let split = self.split
+            .map(|pos| Method::Split(Part::absolute(pos)));
+        let oob = self.oob
+            .map(|pos| Method::Oob(Part::absolute(pos), self.oob_data_size));
+        let oob_window = self.oob_window
+            .map(|pos| Method::OobUrgent(Part::absolute(pos)));
+        let oob_before_sni = self.oob_before_sni
+            .then(|| Method::Oob(Part::with_sni_flag(0).with_negative_offset(-1), self.oob_data_size));
+        let http_url_split = self.http_url_split
+            .map(|pos| Method::HttpFragmentUrl(Part::absolute(pos)));
+        let split_method = match self.split_method.as_deref() {
+            None => None,
+            Some("first-packet") => Some(Method::Split(Part::absolute(1))),
+            Some(preset) => return Err(format!("unknown split method `{preset}`"))
+        };
+        // a random position replaces a fixed one
+        let tlsrec = match (self.tlsrec_random, self.tlsrec_after_random_byte) {
+            (Some(_), true) => return Err("tlsrec-random and tlsrec-after-random-byte are mutually exclusive".to_owned()),
+            (Some((min, max)), false) => Some(TlsrecStrategy::Random { min, max }),
+            (None, true) => Some(TlsrecStrategy::Random { min: 1, max: 21 }),
+            (None, false) => self.tlsrec.map(|pos| TlsrecStrategy::Fixed(Part::absolute(pos)))
+        };
+        let split_before_sni = self.split_before_sni
+            .then(|| Method::Split(Part::with_sni_flag(0).with_negative_offset(-1)));
+        let split_at = self.split_at
+            .map(|name| Method::Split(Part::with_header_flag(0, name)));
+
+        let methods: Vec<Method> = vec![disorder, split, split_method, split_before_sni, oob, oob_window, oob_before_sni, http_url_split, split_at].into_iter().flatten()
+            .chain(self.methods)
+            .chain(self.split_composite)
+            .collect();
+
+        let mut params = Params {
+            tlsrec,
+            tlsrec_min_size: self.tlsrec_min_size,
+            rewrite_host: self.rewrite_host,
+            disable_oob_fallback: self.disable_oob_fallback,
+            fallback_to_split: self.prefer_split_over_disorder,
+            http_remove_headers: self.http_remove_headers,
+            http_uri_encode: self.http_uri_encode,
+            http_inject_trailer: self.http_inject_trailer,
+            http_version_downgrade: self.http_version_downgrade,
+            upstream_sni: self.upstream_sni,
+            fake_sni_length: self.fake_sni_length.map(usize::from),
+            nodelay_upstream: self.nodelay_upstream,
+            nodelay_client: self.nodelay_client,
+            write_buf_size: self.write_buf_size,
+            tcp_cork: self.tcp_cork,
+            half_duplex: self.half_duplex,
+            trace_http_host: self.trace_http_host,
+            keep_alive_upstream: self.keep_alive_upstream_secs.map(Duration::from_secs),
+            upstream_timeout: self.upstream_timeout_ms.map(Duration::from_millis),
+            tls_hello_max_wait: self.tls_hello_max_wait_ms.map(Duration::from_millis),
+            max_read_retries: self.max_read_retries,
+            skip_desync_on_resumption: self.skip_desync_on_resumption,
+            skip_desync_on_ech: self.skip_desync_on_ech,
+            skip_ports: self.skip_ports,
+            desync_ports: self.auto_detect_port.then(|| DESYNC_PORTS.into_iter().collect()),
+            split_count: self.split_count,
+            position_parity,
+            segment_count_limit: self.segment_count_limit,
+            split_tls_extensions: self.split_tls_extensions,
+            split_http_headers: self.split_http_headers,
+            split_ipv6: self.split_ipv6,
+            strategy,
+            ip_preference,
+            upstream_local_ip: self.upstream_interface_ip,
+            tcp_window_scale: self.tcp_window_scale,
+            tcp_mss: self.tcp_mss,
+            proxy_protocol,
+            inspectors: Vec::new(),
+            methods,
+            retry_params: None,
+            sni_list: None,
+        };
+        params.normalize();
+        if self.split_on_retransmit {
+            params.retry_params = Some(Box::new(params.with_retry_split()));
+        }
+
+        Ok(Config {
+            ip: self.ip,
+            port: self.port,
+            log_format: self.log_format,
+            log_connections_only: self.log_connections_only,
+            socks5_auth_file: self.socks5_auth_file,
+            sni_list_file: self.sni_list_file,
+            status_socket: self.status_socket,
+            wire_log_file: self.wire_log_file,
+            flow_ttl: Duration::from_secs(self.flow_ttl_secs),
+            connection_pool: self.connection_pool,
+            max_connections_per_host: self.max_connections_per_host,
+            upstream,
+            params,
+        })
+    }
+}
+
+// the equivalent cli invocation, every method is emitted as a `--method` spec.
+// custom inspectors and strategy rules have no cli form and are left out
+impl From<&Params> for Vec<String> {
+    fn from(params: &Params) -> Self {
+        let mut args = Vec::new();
+        let mut push = |name: &str, value: String| {
+            args.push(format!("--{name}"));
+            args.push(value);
+        };
+        for method in &params.methods {
+            push("method", method.to_string());
+        }
+        match &params.tlsrec {
+            None => {}
+            Some(TlsrecStrategy::Fixed(part)) => push("tlsrec", part.pos.to_string()),
+            Some(TlsrecStrategy::Random { min, max }) => push("tlsrec-random", format!("{min}-{max}"))
+        }
+        push("tlsrec-min-size", params.tlsrec_min_size.to_string());
+        if let Some((from, to)) = &params.rewrite_host {
+            push("rewrite-host", format!("{from}={to}"));
+        }
+        for name in &params.http_remove_headers {
+            push("http-remove-header", name.clone());
+        }
+        if let Some(keyword) = &params.http_uri_encode {
+            push("http-uri-encode", keyword.clone());
+        }
+        if let Some((name, value)) = &params.http_inject_trailer {
+            push("http-inject-trailer", format!("{name}:{value}"));
+        }
+        if let Some(sni) = &params.upstream_sni {
+            push("upstream-sni", sni.clone());
+        }
+        if let Some(len) = params.fake_sni_length {
+            push("fake-sni-length", len.to_string());
+        }
+        if let Some(ip) = params.upstream_local_ip {
+            push("upstream-interface-ip", ip.to_string());
+        }
+        if let Some(scale) = params.tcp_window_scale {
+            push("tcp-window-scale", scale.to_string());
+        }
+        if let Some(mss) = params.tcp_mss {
+            push("tcp-mss", mss.to_string());
+        }
+        push("tcp-nodelay-upstream", params.nodelay_upstream.to_string());
+        push("tcp-nodelay-client", params.nodelay_client.to_string());
+        if let Some(size) = params.write_buf_size {
+            push("write-buf-size", size.to_string());
+        }
+        if let Some(idle) = params.keep_alive_upstream {
+            push("keep-alive-upstream", idle.as_secs().to_string());
+        }
+        if let Some(timeout) = params.upstream_timeout {
+            push("upstream-timeout-ms", timeout.as_millis().to_string());
+        }
+        if let Some(wait) = params.tls_hello_max_wait {
+            push("tls-client-hello-max-wait", wait.as_millis().to_string());
+        }
+        push("max-read-retries", params.max_read_retries.to_string());
+        if let Some(count) = params.split_count {
+            push("split-count", count.to_string());
+        }
+        push("segment-count-limit", params.segment_count_limit.to_string());
+        match params.position_parity {
+            None => {}
+            Some(Parity::Even) => push("split-parity", "even".to_owned()),
+            Some(Parity::Odd) => push("split-parity", "odd".to_owned())
+        }
+        let mut ports: Vec<_> = params.skip_ports.iter().collect();
+        ports.sort();
+        for port in ports {
+            push("no-desync-port", port.to_string());
+        }
+        if params.desync_ports.is_some() {
+            args.push("--auto-detect-port".to_owned());
+        }
+        if params.skip_desync_on_resumption {
+            args.push("--skip-desync-on-resumption".to_owned());
+        }
+        if params.http_version_downgrade {
+            args.push("--http-version-downgrade".to_owned());
+        }
+        if params.split_tls_extensions {
+            args.push("--split-tls-extensions".to_owned());
+        }
+        if params.split_http_headers {
+            args.push("--split-http-headers".to_owned());
+        }
+        if params.split_ipv6 {
+            args.push("--split-ipv6".to_owned());
+        }
+        if params.retry_params.is_some() {
+            args.push("--split-on-retransmit".to_owned());
+        }
+        if params.disable_oob_fallback {
+            args.push("--disable-oob-fallback".to_owned());
+        }
+        if params.fallback_to_split {
+            args.push("--prefer-split-over-disorder".to_owned());
+        }
+        if params.tcp_cork {
+            args.push("--tcp-cork".to_owned());
+        }
+        if params.half_duplex {
+            args.push("--half-duplex".to_owned());
+        }
+        if params.trace_http_host {
+            args.push("--trace-http-host".to_owned());
+        }
+        if params.skip_desync_on_ech {
+            args.push("--skip-desync-on-ech".to_owned());
+        }
+        match params.proxy_protocol {
+            None => {}
+            Some(ProxyProtocol::V1) => args.push("--proxy-protocol-v1".to_owned()),
+            Some(ProxyProtocol::V2) => args.push("--proxy-protocol-v2".to_owned())
+        }
+        match params.ip_preference {
+            IpPreference::None => {}
+            IpPreference::PreferV4 => args.push("--prefer-ipv4".to_owned()),
+            IpPreference::PreferV6 => args.push("--prefer-ipv6".to_owned())
+        }
+        args
+    }
+}
+
+fn env_var<T: FromStr>(name: &str) -> Result<Option<T>, String>
+where
+    T::Err: Display,
+{
+    match env::var(name) {
+        Ok(value) => value.parse().map(Some).map_err(|e| format!("{name}: {e}")),
+        Err(_) => Ok(None),
+    }
+}
+
+// takes the same words as clap's BoolishValueParser, e.g. `1`, `yes` or `off`
+fn env_flag(name: &str) -> Result<Option<bool>, String> {
+    match env::var(name) {
+        Ok(value) => BoolishValueParser::new()
+            .parse_ref(&clap::Command::new("rust-dpi"), None, value.as_ref())
+            .map(Some)
+            .map_err(|_| format!("{name}: invalid boolean `{value}`")),
+        Err(_) => Ok(None),
+    }
+}
+
+fn set<T>(field: &mut T, value: Option<T>) {
+    if let Some(value) = value {
+        *field = value;
+    }
+}
+
+fn set_opt<T>(field: &mut Option<T>, value: Option<T>) {
+    if value.is_some() {
+        *field = value;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::cli;
+    use std::sync::Mutex;
+
+    // the environment is shared by every test thread
+    static ENV: Mutex<()> = Mutex::new(());
+
+    fn build_with_env(vars: &[(&str, &str)], args: &[&str]) -> Result<Config, String> {
+        let _guard = ENV.lock().unwrap_or_else(|err| err.into_inner());
+        for (name, value) in vars {
+            env::set_var(name, value);
+        }
+        let matches = cli().try_get_matches_from(std::iter::once("rust-dpi").chain(args.iter().copied())).unwrap();
+        let config = ConfigBuilder::default()
+            .apply_env()
+            .map(|builder| builder.apply_matches(&matches))
+            .and_then(ConfigBuilder::build);
+        for (name, _) in vars {
+            env::remove_var(name);
+        }
+        config
+    }
+
+    #[test]
+    fn env_vars_set_params() {
+        let config = build_with_env(&[
+            ("RUST_DPI_PORT", "2080"),
+            ("RUST_DPI_SPLIT", "3"),
+            ("RUST_DPI_METHODS", "disorder:1:sni, oob:5"),
+            ("RUST_DPI_HTTP_REMOVE_HEADER", "User-Agent, ,Accept"),
+            ("RUST_DPI_TCP_CORK", "true"),
+            ("RUST_DPI_SEGMENT_COUNT_LIMIT", "4"),
+        ], &[]).unwrap();
+        assert_eq!(config.port, 2080);
+        // ordered by position
+        assert_eq!(config.params.methods, [
+            Method::Disorder(Part::with_sni_flag(1)),
+            Method::Split(Part::absolute(3)),
+            Method::Oob(Part::absolute(5), 1),
+        ]);
+        assert_eq!(config.params.http_remove_headers, ["User-Agent", "Accept"]);
+        assert!(config.params.tcp_cork);
+        assert_eq!(config.params.segment_count_limit, 4);
+    }
+
+    #[test]
+    fn invalid_env_var_names_the_variable() {
+        let err = build_with_env(&[("RUST_DPI_SPLIT", "three")], &[]).err().unwrap();
+        assert!(err.starts_with("RUST_DPI_SPLIT: "), "{err}");
+        let err = build_with_env(&[("RUST_DPI_METHODS", "split")], &[]).err().unwrap();
+        assert!(err.starts_with("RUST_DPI_METHODS: "), "{err}");
+    }
+
+    #[test]
+    fn boolean_env_vars_take_clap_words() {
+        let config = build_with_env(&[
+            ("RUST_DPI_TCP_CORK", "1"),
+            ("RUST_DPI_HALF_DUPLEX", "yes"),
+            ("RUST_DPI_TCP_NODELAY_UPSTREAM", "off"),
+        ], &[]).unwrap();
+        assert!(config.params.tcp_cork);
+        assert!(config.params.half_duplex);
+        assert!(!config.params.nodelay_upstream);
+        let err = build_with_env(&[("RUST_DPI_TCP_CORK", "maybe")], &[]).err().unwrap();
+        assert_eq!(err, "RUST_DPI_TCP_CORK: invalid boolean `maybe`");
+    }
+
+    #[test]
+    fn cli_args_override_env_vars() {
+        let config = build_with_env(
+            &[("RUST_DPI_PORT", "2080"), ("RUST_DPI_SPLIT", "3"), ("RUST_DPI_TLSREC", "10")],
+            &["--port", "3080", "--split", "7"],
+        ).unwrap();
+        assert_eq!(config.port, 3080);
+        assert_eq!(config.params.methods, [Method::Split(Part::absolute(7))]);
+        // not given on the command line, the env var stays
+        assert!(matches!(config.params.tlsrec, Some(TlsrecStrategy::Fixed(Part { pos: 10, .. }))));
+    }
+
+    fn params_from(args: &[String]) -> Params {
+        let matches = cli().try_get_matches_from(std::iter::once("rust-dpi".to_owned()).chain(args.iter().cloned())).unwrap();
+        ConfigBuilder::default().apply_matches(&matches).build().unwrap().params
+    }
+
+    #[test]
+    fn params_round_trip_through_cli_args() {
+        let args: Vec<String> = [
+            "--split", "3", "--method", "disorder:-1:sni", "--method", "oob-window:2:host",
+            "--method", "split:0:header=Host", "--tlsrec-random", "4-9", "--tlsrec-min-size", "64",
+            "--rewrite-host", "a.com=b.com", "--oob", "5", "--oob-data-size", "2", "--http-remove-header", "Accept",
+            "--http-uri-encode", "blocked", "--http-inject-trailer", "X-A:1", "--upstream-sni", "example.com",
+            "--tcp-nodelay-upstream", "false", "--keep-alive-upstream", "30", "--upstream-timeout-ms", "1500",
+            "--split-count", "3", "--segment-count-limit", "9", "--split-parity", "odd",
+            "--no-desync-port", "25", "--no-desync-port", "8443", "--auto-detect-port",
+            "--split-on-retransmit", "--tcp-cork", "--half-duplex", "--proxy-protocol-v2", "--prefer-ipv6",
+        ].into_iter().map(str::to_owned).collect();
+        let params = params_from(&args);
+        let emitted = Vec::from(&params);
+        let round_tripped = params_from(&emitted);
+
+        assert_eq!(Vec::from(&round_tripped), emitted);
+        assert_eq!(round_tripped.methods, params.methods);
+        assert!(matches!(round_tripped.tlsrec, Some(TlsrecStrategy::Random { min: 4, max: 9 })));
+        assert!(emitted.contains(&"oob:5:size=2".to_owned()));
+        assert_eq!(round_tripped.skip_ports, params.skip_ports);
+        assert_eq!(round_tripped.desync_ports, params.desync_ports);
+        assert!(round_tripped.retry_params.is_some());
+    }
+
+    #[test]
+    fn defaults_without_env_or_args() {
+        let config = build_with_env(&[], &[]).unwrap();
+        assert_eq!(config.port, 1080);
+        assert!(config.params.methods.is_empty());
+        assert_eq!(config.params.segment_count_limit, 50);
+    }
+
+    #[test]
+    fn tcp_timestamps_check_fails_the_build_unless_they_are_off() {
+        let err = build_with_env(&[], &["--check-tcp-timestamps"]).err();
+        match platform::tcp_timestamps_enabled() {
+            Some(false) => assert_eq!(err, None),
+            Some(true) => assert!(err.unwrap().contains("net.ipv4.tcp_timestamps=0")),
+            None => assert!(err.unwrap().contains("can't read")),
+        }
+    }
+}
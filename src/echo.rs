@@ -0,0 +1,34 @@
+use std::io::Result;
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{error, info, info_span, Instrument};
+
+// dev helper: echoes every connection back and logs each read, so the
+// segments produced by desync can be inspected through the proxy
+pub async fn run(port: u16) -> Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", port)).await?;
+    info!(port, "echo server listening");
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        tokio::spawn(async move {
+            if let Err(err) = echo(stream).await {
+                error!("{err}");
+            }
+        }.instrument(info_span!("echo", %peer)));
+    }
+}
+
+async fn echo(mut stream: TcpStream) -> Result<()> {
+    let mut buf = [0; 16384];
+    loop {
+        let n = stream.read(&mut buf).await?;
+        if n == 0 {
+            return Ok(());
+        }
+        let head: String = buf[..n.min(16)].iter().map(|b| format!("{b:02x}")).collect();
+        info!(bytes = n, head, "echo read");
+        stream.write_all(&buf[..n]).await?;
+    }
+}
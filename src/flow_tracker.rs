@@ -0,0 +1,132 @@
+use dashmap::DashMap;
+use std::{
+    net::IpAddr,
+    sync::{Arc, Weak},
+    time::Duration,
+};
+use tokio::time::{self, Instant};
+
+// counts connections to the same destination from the same client opened
+// within `ttl` after the previous one closed. keyed by client ip, the source
+// port changes on every reconnect
+#[derive(Debug)]
+pub struct FlowTracker {
+    flows: DashMap<(IpAddr, String), Flow>,
+    ttl: Duration,
+}
+
+#[derive(Debug)]
+struct Flow {
+    retries: u32,
+    // connections of this flow still open
+    active: u32,
+    // None while the first connection is still open
+    closed: Option<Instant>,
+}
+
+impl Flow {
+    fn is_live(&self, now: Instant, ttl: Duration) -> bool {
+        self.active > 0 || self.closed.is_some_and(|closed| now - closed <= ttl)
+    }
+}
+
+// one open connection of a flow. the flow counts as closed once every
+// connection to it is dropped
+#[derive(Debug)]
+pub struct OpenFlow {
+    tracker: Arc<FlowTracker>,
+    key: (IpAddr, String),
+    pub retries: u32,
+}
+
+impl FlowTracker {
+    pub fn new(ttl: Duration) -> Arc<Self> {
+        let tracker = Arc::new(FlowTracker { flows: DashMap::new(), ttl });
+        tokio::spawn(evict(Arc::downgrade(&tracker), ttl));
+        tracker
+    }
+
+    // `retries` is how many times the client came back after the flow had
+    // closed or failed, 0 for a new one. connections opened while another
+    // is still open, like a browser's parallel ones, belong to the same
+    // attempt and don't count
+    pub fn open(self: &Arc<Self>, client: IpAddr, dst: String) -> OpenFlow {
+        let now = Instant::now();
+        let key = (client, dst);
+        let mut flow = self.flows.entry(key.clone())
+            .or_insert(Flow { retries: 0, active: 0, closed: None });
+        if flow.active == 0 {
+            flow.retries = match flow.closed {
+                Some(closed) if now - closed <= self.ttl => flow.retries + 1,
+                _ => 0,
+            };
+        }
+        flow.active += 1;
+        let retries = flow.retries;
+        drop(flow);
+        OpenFlow { tracker: self.clone(), key, retries }
+    }
+}
+
+impl Drop for OpenFlow {
+    fn drop(&mut self) {
+        if let Some(mut flow) = self.tracker.flows.get_mut(&self.key) {
+            flow.active -= 1;
+            flow.closed = Some(Instant::now());
+        }
+    }
+}
+
+async fn evict(tracker: Weak<FlowTracker>, ttl: Duration) {
+    let mut interval = time::interval(ttl);
+    loop {
+        interval.tick().await;
+        let Some(tracker) = tracker.upgrade() else {
+            return;
+        };
+        let now = Instant::now();
+        tracker.flows.retain(|_, flow| flow.is_live(now, ttl));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::net::Ipv4Addr;
+
+    const CLIENT: IpAddr = IpAddr::V4(Ipv4Addr::LOCALHOST);
+
+    #[tokio::test]
+    async fn reconnect_after_close_is_a_retry() {
+        let tracker = FlowTracker::new(Duration::from_secs(60));
+        let first = tracker.open(CLIENT, "example.com:443".to_owned());
+        assert_eq!(first.retries, 0);
+        drop(first);
+
+        assert_eq!(tracker.open(CLIENT, "example.com:443".to_owned()).retries, 1);
+        assert_eq!(tracker.open(CLIENT, "example.org:443".to_owned()).retries, 0);
+    }
+
+    #[tokio::test]
+    async fn concurrent_connections_are_not_retries() {
+        let tracker = FlowTracker::new(Duration::from_secs(60));
+        let first = tracker.open(CLIENT, "example.com:443".to_owned());
+        let second = tracker.open(CLIENT, "example.com:443".to_owned());
+        assert_eq!((first.retries, second.retries), (0, 0));
+
+        // the flow is still open while one of its connections is
+        drop(first);
+        assert_eq!(tracker.open(CLIENT, "example.com:443".to_owned()).retries, 0);
+        drop(second);
+        assert_eq!(tracker.open(CLIENT, "example.com:443".to_owned()).retries, 1);
+    }
+
+    #[tokio::test]
+    async fn reconnect_after_ttl_starts_over() {
+        let tracker = FlowTracker::new(Duration::from_millis(10));
+        drop(tracker.open(CLIENT, "example.com:443".to_owned()));
+        time::sleep(Duration::from_millis(30)).await;
+
+        assert_eq!(tracker.open(CLIENT, "example.com:443".to_owned()).retries, 0);
+    }
+}
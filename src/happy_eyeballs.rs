@@ -0,0 +1,112 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    net::{IpAddr, SocketAddr},
+    time::Duration,
+};
+use crate::platform;
+use serde::{Deserialize, Serialize};
+use tokio::{
+    net::{lookup_host, TcpSocket, TcpStream},
+    sync::Notify,
+    time,
+};
+
+// rfc 8305 head start for ipv6 when no family is preferred
+const IPV6_HEAD_START: Duration = Duration::from_millis(250);
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum IpPreference {
+    #[default]
+    None,
+    PreferV4,
+    PreferV6,
+}
+
+// how upstream sockets are set up before they connect
+#[derive(Clone, Copy, Debug, Default)]
+pub struct ConnectOptions {
+    pub ip_preference: IpPreference,
+    pub local_ip: Option<IpAddr>,
+    pub window_scale: Option<u8>,
+    pub mss: Option<u32>,
+}
+
+// with a preference both families start at once and the preferred one wins a tie.
+// a local address limits the attempts to its own family
+pub async fn connect_happy_eyeballs(host: &str, port: u16, options: ConnectOptions) -> Result<TcpStream> {
+    let (v6, v4): (Vec<_>, Vec<_>) = lookup_host((host, port)).await?
+        .filter(|addr| options.local_ip.is_none_or(|local| local.is_ipv6() == addr.is_ipv6()))
+        .partition(SocketAddr::is_ipv6);
+    let (first, second, delay) = match options.ip_preference {
+        IpPreference::None => (v6, v4, IPV6_HEAD_START),
+        IpPreference::PreferV4 => (v4, v6, Duration::ZERO),
+        IpPreference::PreferV6 => (v6, v4, Duration::ZERO),
+    };
+
+    // the second family starts early if the first one fails before the delay is up
+    let primary_failed = Notify::new();
+    let primary = connect_any(first, options);
+    let secondary = async {
+        tokio::select! {
+            _ = time::sleep(delay) => {}
+            _ = primary_failed.notified() => {}
+        }
+        connect_any(second, options).await
+    };
+    tokio::pin!(primary, secondary);
+
+    let (mut primary_err, mut secondary_err) = (None, None);
+    loop {
+        tokio::select! {
+            biased;
+            res = &mut primary, if primary_err.is_none() => match res {
+                Ok(stream) => return Ok(stream),
+                Err(err) => {
+                    primary_failed.notify_one();
+                    primary_err = Some(err);
+                }
+            },
+            res = &mut secondary, if secondary_err.is_none() => match res {
+                Ok(stream) => return Ok(stream),
+                Err(err) => secondary_err = Some(err),
+            },
+        }
+        match (primary_err.take(), secondary_err.take()) {
+            // an empty family only reports that it had no addresses
+            (Some(primary), Some(secondary)) if primary.kind() == ErrorKind::NotFound => return Err(secondary),
+            (Some(primary), Some(_)) => return Err(primary),
+            (primary, secondary) => (primary_err, secondary_err) = (primary, secondary),
+        }
+    }
+}
+
+async fn connect_any(addrs: Vec<SocketAddr>, options: ConnectOptions) -> Result<TcpStream> {
+    let mut last_err = Error::new(ErrorKind::NotFound, "no addresses to connect to");
+    for addr in addrs {
+        match connect_from(addr, options).await {
+            Ok(stream) => return Ok(stream),
+            Err(err) => last_err = err,
+        }
+    }
+    Err(last_err)
+}
+
+// binding the source address picks the outgoing interface on any platform,
+// unlike SO_BINDTODEVICE which is linux only
+pub async fn connect_from(addr: SocketAddr, options: ConnectOptions) -> Result<TcpStream> {
+    let socket = match addr {
+        SocketAddr::V4(_) => TcpSocket::new_v4()?,
+        SocketAddr::V6(_) => TcpSocket::new_v6()?,
+    };
+    if let Some(local) = options.local_ip {
+        socket.bind(SocketAddr::new(local, 0))?;
+    }
+    if let Some(scale) = options.window_scale {
+        platform::set_window_scale(&socket, scale)?;
+    }
+    if let Some(mss) = options.mss {
+        platform::set_mss(&socket, mss)?;
+    }
+    socket.connect(addr).await
+}
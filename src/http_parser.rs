@@ -0,0 +1,75 @@
+use memchr::memchr;
+
+const METHODS: [&str; 9] = [
+    "HEAD", "GET", "POST", "PUT", "DELETE",
+    "OPTIONS", "CONNECT", "TRACE", "PATCH"
+];
+
+// zero-copy view of an HTTP/1.x request head, offsets are positions in the original buffer
+pub struct HttpRequest<'a> {
+    buf: &'a [u8],
+    pub method: &'static str,
+    pub path: &'a [u8],
+    pub path_offset: usize,
+    pub version: &'a [u8],
+    headers_offset: usize
+}
+
+pub struct HttpHeader<'a> {
+    pub name: &'a [u8],
+    pub value: &'a [u8],
+    pub value_offset: usize
+}
+
+impl<'a> HttpRequest<'a> {
+    // a request head cut short stops at the last complete header line
+    pub fn headers(&self) -> impl Iterator<Item = HttpHeader<'a>> {
+        let buf = self.buf;
+        let mut line_start = self.headers_offset;
+        std::iter::from_fn(move || {
+            let line_end = line_start + memchr(b'\n', buf.get(line_start..)?)?;
+            let line = &buf[line_start..line_end];
+            let line = line.strip_suffix(b"\r").unwrap_or(line);
+            if line.is_empty() {
+                return None;
+            }
+            let colon = memchr(b':', line)?;
+            let spaces = line[colon + 1..].iter().take_while(|&&b| b == b' ' || b == b'\t').count();
+            let header = HttpHeader {
+                name: &line[..colon],
+                value: line[colon + 1 + spaces..].trim_ascii_end(),
+                value_offset: line_start + colon + 1 + spaces
+            };
+            line_start = line_end + 1;
+            Some(header)
+        })
+    }
+
+    pub fn header(&self, name: &str) -> Option<HttpHeader<'a>> {
+        self.headers().find(|header| header.name.eq_ignore_ascii_case(name.as_bytes()))
+    }
+
+    pub fn host_offset(&self) -> Option<usize> {
+        self.header("host").map(|header| header.value_offset)
+    }
+}
+
+pub fn parse_http_request(buf: &[u8]) -> Option<HttpRequest<'_>> {
+    let method = METHODS.into_iter()
+        .find(|method| buf.starts_with(method.as_bytes()) && buf.get(method.len()) == Some(&b' '))?;
+    let path_offset = method.len() + 1;
+    let line_end = memchr(b'\n', buf).unwrap_or(buf.len());
+    let line = buf[path_offset..line_end].trim_ascii_end();
+    let (path, version) = match memchr(b' ', line) {
+        Some(space) => (&line[..space], &line[space + 1..]),
+        None => (line, &line[line.len()..])
+    };
+    Some(HttpRequest {
+        buf,
+        method,
+        path,
+        path_offset,
+        version,
+        headers_offset: (line_end + 1).min(buf.len())
+    })
+}
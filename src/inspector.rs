@@ -0,0 +1,72 @@
+use crate::{http_parser::parse_http_request, packets::{is_dtls_hello, is_tls_hello}, tls_parser::TlsParseError};
+use std::fmt;
+use tracing::debug;
+
+pub trait Inspector: Send + Sync {
+    fn inspect(&self, buffer: &[u8]) -> Option<InspectionResult>;
+}
+
+impl fmt::Debug for dyn Inspector {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("Inspector")
+    }
+}
+
+// `split_hint` is the offset that sni/host flagged parts are resolved against
+pub struct InspectionResult {
+    pub protocol: String,
+    pub split_hint: Option<usize>,
+}
+
+pub struct TlsInspector;
+
+impl TlsInspector {
+    // the errors tell tls that can't be desynced by sni apart from anything
+    // that isn't tls, see `is_tls_hello`
+    pub fn try_inspect(&self, buffer: &[u8]) -> Result<InspectionResult, TlsParseError> {
+        Ok(InspectionResult {
+            protocol: "tls".to_owned(),
+            split_hint: Some(is_tls_hello(buffer)?),
+        })
+    }
+}
+
+impl Inspector for TlsInspector {
+    fn inspect(&self, buffer: &[u8]) -> Option<InspectionResult> {
+        self.try_inspect(buffer).ok()
+    }
+}
+
+// dtls over tcp. not `tls`, the tls record splitting doesn't apply to it,
+// only sni flagged positions
+pub struct DtlsInspector;
+
+impl Inspector for DtlsInspector {
+    fn inspect(&self, buffer: &[u8]) -> Option<InspectionResult> {
+        let sni_offset = is_dtls_hello(buffer)?;
+        debug!(sni_offset, "dtls ClientHello");
+        Some(InspectionResult {
+            protocol: "dtls".to_owned(),
+            split_hint: Some(sni_offset),
+        })
+    }
+}
+
+pub struct HttpInspector;
+
+impl Inspector for HttpInspector {
+    fn inspect(&self, buffer: &[u8]) -> Option<InspectionResult> {
+        let request = parse_http_request(buffer)?;
+        debug!(
+            method = request.method,
+            path = %String::from_utf8_lossy(request.path),
+            version = %String::from_utf8_lossy(request.version),
+            "http request"
+        );
+        Some(InspectionResult {
+            protocol: "http".to_owned(),
+            // HTTP/1.0 requests may omit Host, fall back to the request-target
+            split_hint: request.host_offset().or(Some(request.path_offset)),
+        })
+    }
+}
@@ -0,0 +1,66 @@
+use std::fmt;
+use tracing::{Event, Subscriber};
+use tracing_subscriber::{
+    fmt::{
+        format::{Format, Json, Writer},
+        FmtContext, FormatEvent, FormatFields,
+    },
+    registry::LookupSpan,
+};
+
+// the json event format of tracing-subscriber with the timestamp written as
+// `ts`. its formatter always puts the timestamp first and has no way to
+// rename it
+pub struct TsJson(pub Format<Json>);
+
+impl<S, N> FormatEvent<S, N> for TsJson
+where
+    S: Subscriber + for<'lookup> LookupSpan<'lookup>,
+    N: for<'writer> FormatFields<'writer> + 'static,
+{
+    fn format_event(&self, ctx: &FmtContext<'_, S, N>, mut writer: Writer<'_>, event: &Event<'_>) -> fmt::Result {
+        let mut line = String::new();
+        self.0.format_event(ctx, Writer::new(&mut line), event)?;
+        match line.strip_prefix("{\"timestamp\":") {
+            Some(rest) => write!(writer, "{{\"ts\":{rest}"),
+            None => writer.write_str(&line),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::{Arc, Mutex};
+    use tracing_subscriber::fmt::format::JsonFields;
+
+    #[test]
+    fn timestamp_is_written_as_ts() {
+        let out = Arc::new(Mutex::new(Vec::new()));
+        let writer = out.clone();
+        let subscriber = tracing_subscriber::fmt()
+            .fmt_fields(JsonFields::new())
+            .event_format(TsJson(tracing_subscriber::fmt::format().json()))
+            .with_writer(move || WriterGuard(writer.clone()))
+            .finish();
+        tracing::subscriber::with_default(subscriber, || tracing::info!("hello"));
+
+        let line = String::from_utf8(out.lock().unwrap().clone()).unwrap();
+        let event: serde_json::Value = serde_json::from_str(&line).unwrap();
+        assert!(event.get("ts").is_some());
+        assert!(event.get("timestamp").is_none());
+        assert_eq!(event["fields"]["message"], "hello");
+    }
+
+    struct WriterGuard(Arc<Mutex<Vec<u8>>>);
+
+    impl std::io::Write for WriterGuard {
+        fn write(&mut self, buf: &[u8]) -> std::io::Result<usize> {
+            self.0.lock().unwrap().write(buf)
+        }
+
+        fn flush(&mut self) -> std::io::Result<()> {
+            Ok(())
+        }
+    }
+}
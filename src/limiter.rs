@@ -0,0 +1,60 @@
+use dashmap::DashMap;
+use std::{
+    io::{Error, ErrorKind, Result},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::{
+    sync::{OwnedSemaphorePermit, Semaphore},
+    time::timeout,
+};
+use tracing::warn;
+
+// how long a connection over the limit waits for a slot
+const QUEUE_TIMEOUT: Duration = Duration::from_secs(10);
+
+// caps the open upstream connections per `host:port`. connections over the
+// limit wait for a slot instead of being refused
+pub struct ConnectionLimiter {
+    hosts: DashMap<String, Arc<Semaphore>>,
+    limit: usize,
+}
+
+// holds one slot until the upstream connection is closed
+pub struct HostPermit {
+    limiter: Arc<ConnectionLimiter>,
+    dst: String,
+    permit: Option<OwnedSemaphorePermit>,
+}
+
+impl ConnectionLimiter {
+    pub fn new(limit: usize) -> Arc<Self> {
+        Arc::new(ConnectionLimiter { hosts: DashMap::new(), limit })
+    }
+
+    pub async fn acquire(self: &Arc<Self>, dst: String) -> Result<HostPermit> {
+        let semaphore = self.hosts.entry(dst.clone())
+            .or_insert_with(|| Arc::new(Semaphore::new(self.limit)))
+            .clone();
+        match timeout(QUEUE_TIMEOUT, semaphore.acquire_owned()).await {
+            Ok(permit) => Ok(HostPermit {
+                limiter: self.clone(),
+                dst,
+                permit: Some(permit.expect("the semaphore is never closed")),
+            }),
+            Err(_) => {
+                warn!(%dst, limit = self.limit, "upstream connection limit reached");
+                Err(Error::new(ErrorKind::TimedOut, format!("too many connections to {dst}")))
+            }
+        }
+    }
+}
+
+impl Drop for HostPermit {
+    fn drop(&mut self) {
+        drop(self.permit.take());
+        // the map holds the last reference once no connection waits for or
+        // holds a slot. clones are taken under the same shard lock
+        self.limiter.hosts.remove_if(&self.dst, |_, semaphore| Arc::strong_count(semaphore) == 1);
+    }
+}
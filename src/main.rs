@@ -1,72 +1,460 @@
-use clap::{arg, value_parser};
-use packets::{is_http, is_tls_hello, part_tls};
-use socket2::SockRef;
+use auth::{Anonymous, AuthOutput, FileAuth};
+use clap::{arg, builder::BoolishValueParser, value_parser, ArgAction};
+use inspector::{DtlsInspector, HttpInspector, InspectionResult, Inspector, TlsInspector};
+use json_log::TsJson;
+use http_parser::parse_http_request;
+use packets::{
+    cipher_suites_end_offset, downgrade_http_version, find_http_header, has_ech_extension, http_header_offsets, inject_http_trailer, is_chunked_http_response, is_partial_tls_record, is_tls_session_ticket,
+    pad_sni_to_length, part_tls,
+    remove_http_header, replace_sni, rewrite_http_host, sni_end_offset, tls_extension_offsets, uri_encode_keyword, url_start_offset
+};
+use rand::Rng;
+use serde::{Deserialize, Serialize};
 use socks5_server::{
-    auth::NoAuth,
     connection::state::NeedAuthenticate,
     proto::{Address, Error, Reply},
     Command, IncomingConnection, Server,
 };
-use std::{io::Error as IoError, sync::Arc};
+use config::{Config, ConfigBuilder};
+use flow_tracker::FlowTracker;
+use limiter::{ConnectionLimiter, HostPermit};
+use metrics::{ConnectionId, ConnectionMetrics};
+use pool::PreconnectPool;
+use proxy_protocol::ProxyProtocol;
+use relay::{relay_bidirectional, relay_bidirectional_with, relay_half_duplex};
+use sink::DesyncSink;
+use sni_cache::SniCache;
+use sni_list::SniList;
+use strategy::StrategyRule;
+use happy_eyeballs::{connect_from, connect_happy_eyeballs, ConnectOptions, IpPreference};
+use std::{collections::HashSet, io::{Error as IoError, ErrorKind}, net::{IpAddr, SocketAddr}, path::PathBuf, sync::Arc, time::Duration};
 use tokio::{
-    io::{copy_bidirectional, AsyncRead, AsyncReadExt, AsyncWriteExt},
+    io::{AsyncRead, AsyncReadExt, AsyncWriteExt},
     net::{TcpListener, TcpStream},
+    sync::broadcast,
+    task::JoinSet,
+    time::{sleep, timeout},
 };
+use timeout_stream::TimeoutStream;
+use wire_log::{WireLogFile, WireRecorder};
+use tls_parser::{parse_client_hello, TlsParseError};
+use tracing::{debug, error, field, info, info_span, warn, Instrument, Span};
+use tracing_subscriber::fmt::format::JsonFields;
+mod auth;
+mod benchmark;
+mod config;
+#[cfg(feature = "dev")]
+mod echo;
+mod flow_tracker;
+mod happy_eyeballs;
+mod http_parser;
+mod inspector;
+mod json_log;
+mod limiter;
+mod metrics;
+#[cfg(test)]
+mod mock;
 mod packets;
+mod platform;
+mod pool;
+mod probe;
+mod proxy_protocol;
+mod relay;
+mod sink;
+mod sni_cache;
+mod sni_list;
+mod status;
+mod strategy;
+mod timeout_stream;
+mod tls_parser;
+mod utils;
+mod wire_log;
+
+fn cli() -> clap::Command {
+    let command = clap::Command::new("rust-dpi")
+        .version("0.1")
+        .arg(arg!(--ip <VALUE> "[default: 0.0.0.0] [env: RUST_DPI_IP]"))
+        .arg(arg!(--port <VALUE> "[default: 1080] [env: RUST_DPI_PORT]").value_parser(value_parser!(u16)))
+        .arg(arg!(--disorder <VALUE> "[env: RUST_DPI_DISORDER]").value_parser(value_parser!(usize)))
+        .arg(arg!(--split <VALUE> "[env: RUST_DPI_SPLIT]").value_parser(value_parser!(usize)))
+        .arg(arg!(--"split-method" <PRESET> "named split preset, first-packet splits after the first byte [env: RUST_DPI_SPLIT_METHOD]")
+            .value_parser(["first-packet"]))
+        .arg(arg!(--"split-count" <N> "split the first packet into N equal parts [env: RUST_DPI_SPLIT_COUNT]")
+            .value_parser(value_parser!(usize)))
+        .arg(arg!(--"segment-count-limit" <N> "stop applying methods once desync has sent N segments, the rest of the packet goes out in one [default: 50] [env: RUST_DPI_SEGMENT_COUNT_LIMIT]")
+            .value_parser(value_parser!(usize)))
+        .arg(arg!(--"split-parity" <PARITY> "round every split, disorder and oob position down to an even or up to an odd offset [env: RUST_DPI_SPLIT_PARITY]")
+            .value_parser(["even", "odd"]))
+        .arg(arg!(--"split-tls-extensions" "split the tls ClientHello before every extension [env: RUST_DPI_SPLIT_TLS_EXTENSIONS]"))
+        .arg(arg!(--"split-http-headers" "split the http request before every header line [env: RUST_DPI_SPLIT_HTTP_HEADERS]"))
+        .arg(arg!(--"split-on-retransmit" "when a client reconnects to the same destination within --flow-ttl after its last connection there closed, also split after the first byte. parallel connections don't count [env: RUST_DPI_SPLIT_ON_RETRANSMIT]"))
+        .arg(arg!(--"split-ipv6" "with an ipv6 upstream also split at byte 40, the ipv6 header length. a heuristic [env: RUST_DPI_SPLIT_IPV6]"))
+        .arg(arg!(--"split-before-sni" "split one byte before the sni, same as --method split:-1:sni [env: RUST_DPI_SPLIT_BEFORE_SNI]"))
+        .arg(arg!(--"split-at" <HEADER> "split http request at the value of the given header [env: RUST_DPI_SPLIT_AT]"))
+        .arg(arg!(--oob <VALUE> "[env: RUST_DPI_OOB]").value_parser(value_parser!(usize)))
+        .arg(arg!(--"prefer-split-over-disorder" "send disorder methods as plain splits where the socket ttl can't be lowered [env: RUST_DPI_PREFER_SPLIT_OVER_DISORDER]"))
+        .arg(arg!(--"disable-oob-fallback" "close the connection instead of sending a plain split where oob is unsupported [env: RUST_DPI_DISABLE_OOB_FALLBACK]"))
+        .arg(arg!(--"oob-data-size" <N> "bytes past the position sent in the oob segment of --oob and --oob-before-sni, the last one is the urgent byte. --method oob:<pos>:size=<N> sets it per method [default: 1] [env: RUST_DPI_OOB_DATA_SIZE]")
+            .value_parser(value_parser!(usize)))
+        .arg(arg!(--"oob-before-sni" "send the byte before the sni as oob data, same as --method oob:-1:sni [env: RUST_DPI_OOB_BEFORE_SNI]"))
+        .arg(arg!(--"oob-window" <VALUE> "send the segment normally, then a fake byte alone as tcp urgent data [env: RUST_DPI_OOB_WINDOW]")
+            .value_parser(value_parser!(usize)))
+        .arg(arg!(--method <SPEC> "<method>:<pos>[:sni|sni-end|host|cipher|header=<name>], oob also takes [:size=<N>] last, may be repeated [env: RUST_DPI_METHODS]")
+            .action(ArgAction::Append)
+            .value_parser(|s: &str| Method::try_from(s)))
+        .arg(arg!(--"split-composite" <PAIRS> "comma separated --method specs, e.g. disorder:1,split:40,oob:80 [env: RUST_DPI_SPLIT_COMPOSITE]")
+            .value_parser(parse_split_composite))
+        .arg(arg!(--"http-url-split" <N> "split the http request line N bytes into the url [env: RUST_DPI_HTTP_URL_SPLIT]")
+            .value_parser(value_parser!(usize)))
+        .arg(arg!(--tlsrec <VALUE> "[env: RUST_DPI_TLSREC]").value_parser(value_parser!(usize)))
+        .arg(arg!(--"tlsrec-random" <RANGE> "split the tls record at a random position in <min>-<max> for every connection [env: RUST_DPI_TLSREC_RANDOM]")
+            .value_parser(parse_range))
+        .arg(arg!(--"tlsrec-after-random-byte" "split the tls record after a random byte among the first 20 for every connection [env: RUST_DPI_TLSREC_AFTER_RANDOM_BYTE]")
+            .conflicts_with("tlsrec-random"))
+        .arg(arg!(--"tlsrec-min-size" <N> "skip tlsrec for hellos shorter than N bytes [default: 20] [env: RUST_DPI_TLSREC_MIN_SIZE]")
+            .value_parser(value_parser!(usize)))
+        .arg(arg!(--"strategy-file" <PATH> "per protocol method rules, replace the methods above when a rule matches [env: RUST_DPI_STRATEGY_FILE]")
+            .value_parser(value_parser!(PathBuf)))
+        .arg(arg!(--"rewrite-host" <VALUE> "rewrite http host header, format: <from>=<to> [env: RUST_DPI_REWRITE_HOST]")
+            .value_parser(parse_host_rewrite))
+        .arg(arg!(--"http-uri-encode" <KEYWORD> "percent-encode one character of KEYWORD where it appears in the http request target [env: RUST_DPI_HTTP_URI_ENCODE]"))
+        .arg(arg!(--"http-remove-header" <NAME> "drop the first header with this name from http requests, may be repeated [env: RUST_DPI_HTTP_REMOVE_HEADER]")
+            .action(ArgAction::Append))
+        .arg(arg!(--"http-inject-trailer" <TRAILER> "append a <name>:<value> trailer to chunked http responses [env: RUST_DPI_HTTP_INJECT_TRAILER]")
+            .value_parser(parse_http_trailer))
+        .arg(arg!(--"http-version-downgrade" "rewrite HTTP/1.1 to HTTP/1.0 in the request line [env: RUST_DPI_HTTP_VERSION_DOWNGRADE]"))
+        .arg(arg!(--"upstream-sni" <HOSTNAME> "replace the sni of tls ClientHellos sent upstream [env: RUST_DPI_UPSTREAM_SNI]")
+            .value_parser(parse_hostname))
+        .arg(arg!(--"fake-sni-length" <N> "pad the sni of tls ClientHellos sent upstream with zero bytes to N bytes [env: RUST_DPI_FAKE_SNI_LENGTH]")
+            .value_parser(value_parser!(u8).range(1..)))
+        .arg(arg!(--"tcp-nodelay-upstream" <BOOL> "set TCP_NODELAY on upstream sockets [default: true] [env: RUST_DPI_TCP_NODELAY_UPSTREAM]")
+            .value_parser(BoolishValueParser::new()))
+        .arg(arg!(--"tcp-nodelay-client" <BOOL> "set TCP_NODELAY on client sockets [default: false] [env: RUST_DPI_TCP_NODELAY_CLIENT]")
+            .value_parser(BoolishValueParser::new()))
+        .arg(arg!(--"tcp-cork" "wrap every split fragment in TCP_CORK so it leaves as one segment, linux only [env: RUST_DPI_TCP_CORK]"))
+        .arg(arg!(--"half-duplex" "after the hello only relay the upstream answer to the client, for one-shot protocols like http/1.0 [env: RUST_DPI_HALF_DUPLEX]")
+            .alias("no-copy-bidirectional"))
+        .arg(arg!(--"write-buf-size" <N> "SO_SNDBUF for upstream sockets, linux doubles it internally [env: RUST_DPI_WRITE_BUF_SIZE]")
+            .value_parser(value_parser!(usize)))
+        .arg(arg!(--"keep-alive-upstream" <SECS> "send tcp keepalive probes after SECS of idle time on upstream sockets [env: RUST_DPI_KEEP_ALIVE_UPSTREAM]")
+            .value_parser(value_parser!(u64).range(1..)))
+        .arg(arg!(--"log-format" <FORMAT> "[default: text] [env: RUST_DPI_LOG_FORMAT]")
+            .value_parser(["text", "json"]))
+        .arg(arg!(--"log-connections-only" "log every connection at info level when it opens and closes, and leave out the per method output [env: RUST_DPI_LOG_CONNECTIONS_ONLY]"))
+        .arg(arg!(--"trace-http-host" "log the Host header of every plain http connection. off by default, it records the sites clients visit [env: RUST_DPI_TRACE_HTTP_HOST]"))
+        .arg(arg!(--"upstream-timeout-ms" <MS> "deadline for each read/write on the upstream socket [env: RUST_DPI_UPSTREAM_TIMEOUT_MS]")
+            .value_parser(value_parser!(u64)))
+        .arg(arg!(--"tls-client-hello-max-wait" <MS> "wait up to MS for a tls ClientHello split across segments [env: RUST_DPI_TLS_CLIENT_HELLO_MAX_WAIT]")
+            .value_parser(value_parser!(u64)))
+        .arg(arg!(--"max-read-retries" <N> "retry an empty or interrupted read of the hello N times, 1ms apart [default: 3] [env: RUST_DPI_MAX_READ_RETRIES]")
+            .value_parser(value_parser!(u32)))
+        .arg(arg!(--"skip-desync-on-ech" "pass through tls hellos using encrypted client hello [env: RUST_DPI_SKIP_DESYNC_ON_ECH]"))
+        .arg(arg!(--"no-desync-port" <PORT> "pass through connections to this destination port, may be repeated [env: RUST_DPI_NO_DESYNC_PORT]")
+            .action(ArgAction::Append)
+            .value_parser(value_parser!(u16)))
+        .arg(arg!(--"auto-detect-port" "desync only connections to common http and https ports: 80, 443, 8080, 8443, 8888 [env: RUST_DPI_AUTO_DETECT_PORT]"))
+        .arg(arg!(--"skip-desync-on-resumption" "pass through tls session resumption hellos [env: RUST_DPI_SKIP_DESYNC_ON_RESUMPTION]"))
+        .arg(arg!(--"prefer-ipv4" "connect over both families at once, ipv4 wins a tie [env: RUST_DPI_PREFER_IPV4]")
+            .conflicts_with("prefer-ipv6"))
+        .arg(arg!(--"prefer-ipv6" "connect over both families at once, ipv6 wins a tie [env: RUST_DPI_PREFER_IPV6]"))
+        .arg(arg!(--"upstream-interface-ip" <IP> "bind upstream sockets to this local address, only targets of its family are reachable [env: RUST_DPI_UPSTREAM_INTERFACE_IP]")
+            .value_parser(value_parser!(IpAddr)))
+        .arg(arg!(--"flow-ttl" <SECS> "forget a client/destination pair SECS after its last connection closed [default: 60] [env: RUST_DPI_FLOW_TTL]")
+            .value_parser(value_parser!(u64)))
+        .arg(arg!(--"connection-pool" <N> "connect N upstream sockets ahead of time per target, a used socket is closed with its session and replaced, not returned [default: 0] [env: RUST_DPI_CONNECTION_POOL]")
+            .value_parser(value_parser!(usize)))
+        .arg(arg!(--"max-upstream-connections-per-host" <N> "keep at most N upstream connections per host:port open, more wait for a free slot [env: RUST_DPI_MAX_UPSTREAM_CONNECTIONS_PER_HOST]")
+            .value_parser(value_parser!(usize)))
+        .arg(arg!(--"tcp-window-scale" <N> "limit the window scale the upstream SYN offers to about N (0-14) by shrinking the receive buffer, which also caps download throughput for the whole connection [env: RUST_DPI_TCP_WINDOW_SCALE]")
+            .value_parser(value_parser!(u8).range(0..=14)))
+        .arg(arg!(--"tcp-mss" <N> "set TCP_MAXSEG before connecting, so the upstream SYN offers an mss of N [env: RUST_DPI_TCP_MSS]")
+            .value_parser(value_parser!(u32).range(88..=65535)))
+        .arg(arg!(--"check-tcp-timestamps" "refuse to start when upstream SYNs would carry tcp timestamps. only a check, they are a system wide setting and are not turned off [env: RUST_DPI_CHECK_TCP_TIMESTAMPS]"))
+        .arg(arg!(--"proxy-protocol-v1" "send a PROXY protocol v1 header with the client address to the upstream [env: RUST_DPI_PROXY_PROTOCOL_V1]")
+            .conflicts_with("proxy-protocol-v2"))
+        .arg(arg!(--"proxy-protocol-v2" "send a binary PROXY protocol v2 header with the client address to the upstream [env: RUST_DPI_PROXY_PROTOCOL_V2]"))
+        .arg(arg!(--"no-socks" "forward plain tcp connections to --upstream-host/--upstream-port instead of serving socks5 [env: RUST_DPI_NO_SOCKS]"))
+        .arg(arg!(--"upstream-host" <HOST> "fixed upstream for --no-socks [env: RUST_DPI_UPSTREAM_HOST]"))
+        .arg(arg!(--"upstream-port" <PORT> "fixed upstream for --no-socks [env: RUST_DPI_UPSTREAM_PORT]")
+            .value_parser(value_parser!(u16)))
+        .arg(arg!(--"sni-list-file" <PATH> "only desync tls connections to the server names in this file, one per line. everything else is passed through, reloaded on SIGHUP [env: RUST_DPI_SNI_LIST_FILE]")
+            .value_parser(value_parser!(PathBuf)))
+        .arg(arg!(--"socks5-auth-file" <PATH> "file of user:pass lines, reloaded on SIGHUP [env: RUST_DPI_SOCKS5_AUTH_FILE]")
+            .value_parser(value_parser!(PathBuf)))
+        .arg(arg!(--"status-socket" <PATH> "serve live stats as json on this unix socket [env: RUST_DPI_STATUS_SOCKET]")
+            .value_parser(value_parser!(PathBuf)))
+        .arg(arg!(--"wire-log-file" <PATH> "append the exact bytes every desync sends upstream to this file, one json line per connection [env: RUST_DPI_WIRE_LOG_FILE]")
+            .value_parser(value_parser!(PathBuf)))
+        .arg(arg!(--"config-dump" "print the settings from the command line and environment as json and exit"))
+        .arg(arg!(--status "print the stats of the instance listening on --status-socket and exit"))
+        .arg(arg!(--"test-connectivity" <TARGET> "check that <host>:<port> is reachable, print the rtt and exit")
+            .value_parser(utils::parse_host_port))
+        .arg(arg!(--probe <TARGET> "try a tls hello to <host>:<port> with one method at a time, print the first that gets an answer and exit")
+            .value_parser(utils::parse_host_port))
+        .arg(arg!(--"throughput-test" <VALUE> "desync to <host>:<port>, which should discard what it gets, send zeros for <seconds> and print the rate: <host>:<port> <seconds>")
+            .num_args(2))
+        .arg(arg!(--benchmark <VALUE> "run synthetic desync benchmark: <connections> <payload_size>")
+            .num_args(2)
+            .value_parser(value_parser!(usize)));
+    #[cfg(feature = "dev")]
+    let command = command
+        .arg(arg!(--"echo-server" <PORT> "also run an echo server on 127.0.0.1:PORT that logs every read")
+            .value_parser(value_parser!(u16)));
+    command
+}
 
 // used template https://github.com/EAimTY/socks5-server/blob/master/socks5-server/examples/simple_socks5.rs
 #[tokio::main]
 async fn main() -> Result<(), IoError> {
-    let matches = clap::Command::new("rust-dpi")
-        .version("0.1")
-        .arg(arg!(--ip <VALUE>).default_value("0.0.0.0"))
-        .arg(arg!(--port <VALUE>).default_value("1080"))
-        .arg(arg!(--disorder <VALUE>).value_parser(value_parser!(usize)))
-        .arg(arg!(--split <VALUE>).value_parser(value_parser!(usize)))
-        .arg(arg!(--oob <VALUE>).value_parser(value_parser!(usize)))
-        .arg(arg!(--tlsrec <VALUE>).value_parser(value_parser!(usize)))
-        .get_matches();
-    
-    let ip = matches.get_one::<String>("ip").expect("need ip");
-    let port = matches.get_one::<String>("port").expect("need port");
-    let tlsrec = matches.get_one::<usize>("tlsrec").map(|pos| Part { pos: pos.clone(), flag: None });
-
-    let disorder = matches.get_one::<usize>("disorder")
-        .map(|pos| Method::Disorder(Part { pos: pos.clone(), flag: None }));
-    let split = matches.get_one::<usize>("split")
-        .map(|pos| Method::Split(Part { pos: pos.clone(), flag: None }));
-    let oob = matches.get_one::<usize>("oob")
-        .map(|pos| Method::Oob(Part { pos: pos.clone(), flag: None }));
+    let matches = cli().get_matches();
 
-    let listener = TcpListener::bind(format!("{ip}:{port}")).await?;
-    let auth = Arc::new(NoAuth) as Arc<_>;
-
-    let server = Server::new(listener, auth);
-    
-    let mut methods: Vec<Method> = vec![disorder, split, oob].into_iter().flatten().collect();
-    methods.sort_by(|a, b|method_part(a).pos.cmp(&method_part(b).pos));
-    
-    let params = Params {
-        tlsrec: tlsrec,
-        methods: methods
-    };
+    let Config { ip, port, log_format, log_connections_only, socks5_auth_file, sni_list_file, status_socket, wire_log_file, flow_ttl, connection_pool, max_connections_per_host, upstream, mut params } = ConfigBuilder::default()
+        .apply_env()
+        .and_then(|builder| builder.apply_matches(&matches).build())
+        .map_err(|err| IoError::new(ErrorKind::InvalidInput, err))?;
 
-    while let Ok((conn, _)) = server.accept().await {
-        let params = params.clone();
+    if matches.get_flag("config-dump") {
+        println!("{}", serde_json::to_string_pretty(&params)?);
+        return Ok(());
+    }
+
+    metrics::set_connections_only(log_connections_only);
+    let subscriber = tracing_subscriber::fmt().with_target(true);
+    match log_format.as_str() {
+        "json" => {
+            let format = tracing_subscriber::fmt::format().json().with_current_span(true).with_span_list(true);
+            subscriber.fmt_fields(JsonFields::new()).event_format(TsJson(format)).init()
+        }
+        _ => subscriber.init()
+    }
+
+    if matches.get_flag("status") {
+        let path = status_socket.ok_or_else(|| IoError::new(ErrorKind::InvalidInput, "--status needs --status-socket"))?;
+        return status::print(&path).await;
+    }
+
+    if let Some((host, target_port)) = matches.get_one::<(String, u16)>("test-connectivity") {
+        match utils::test_connectivity(host, *target_port).await {
+            Ok(rtt) => println!("{host}:{target_port} reachable, rtt {:.1}ms", rtt.as_secs_f64() * 1e3),
+            Err(err) => {
+                eprintln!("{host}:{target_port} unreachable: {err}");
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some((host, target_port)) = matches.get_one::<(String, u16)>("probe") {
+        let result = probe::probe_dpi(host, *target_port, &params).await;
+        match (result.success, result.method_applied) {
+            (true, None) => println!("{host}:{target_port} answered without desync, {}ms", result.latency_ms),
+            (true, Some(method)) => println!("{host}:{target_port} answered with {method}, {}ms\nrecommended: --method {method}", result.latency_ms),
+            (false, _) => {
+                eprintln!("{host}:{target_port} did not answer with any method");
+                std::process::exit(1);
+            }
+        }
+        return Ok(());
+    }
+
+    if let Some(mut test) = matches.get_many::<String>("throughput-test") {
+        let invalid = |err: String| IoError::new(ErrorKind::InvalidInput, err);
+        let (host, target_port) = utils::parse_host_port(test.next().expect("two values")).map_err(invalid)?;
+        let seconds = test.next().expect("two values");
+        let seconds = seconds.parse::<u64>().ok().filter(|&secs| secs > 0)
+            .ok_or_else(|| invalid(format!("invalid number of seconds `{seconds}`")))?;
+        return benchmark::throughput(&host, target_port, Duration::from_secs(seconds), params).await;
+    }
+
+    if let Some(mut bench) = matches.get_many::<usize>("benchmark") {
+        let connections = *bench.next().expect("two values");
+        let payload_size = *bench.next().expect("two values");
+        return benchmark::run(connections, payload_size, params).await;
+    }
+
+    #[cfg(feature = "dev")]
+    if let Some(&echo_port) = matches.get_one::<u16>("echo-server") {
+        tokio::spawn(async move {
+            if let Err(err) = echo::run(echo_port).await {
+                error!("echo server: {err}");
+            }
+        });
+    }
+
+    // the probe blocks, so it runs here and not in the first desync
+    platform::check_oob_support();
+
+    let listener = TcpListener::bind(format!("{ip}:{port}")).await?;
+    info!(%ip, port, args = Vec::<String>::from(&params).join(" "), "listening");
+    if let Some(path) = status_socket {
         tokio::spawn(async move {
-            match handle(conn, params).await {
-                Ok(()) => {}
-                Err(err) => eprintln!("{err}"),
+            if let Err(err) = status::serve(path).await {
+                error!("status socket: {err}");
             }
         });
     }
+    if let Some(path) = sni_list_file {
+        params.set_sni_list(SniList::load(path)?);
+    }
+    let auth = match socks5_auth_file {
+        Some(path) => FileAuth::load(path)? as Arc<_>,
+        None => Arc::new(Anonymous) as Arc<_>
+    };
+
+    let wire_log = match wire_log_file {
+        Some(path) => Some(Arc::new(WireLogFile::open(&path)?)),
+        None => None
+    };
+    let state = Arc::new(State {
+        flows: FlowTracker::new(flow_ttl),
+        sni_cache: SniCache::new(4096),
+        pool: (connection_pool > 0).then(|| PreconnectPool::new(connection_pool, params.connect_options())),
+        limiter: max_connections_per_host.map(ConnectionLimiter::new),
+        wire_log
+    });
+
+    let mut shutdown = shutdown_signal()?;
+    let mut tasks = JoinSet::new();
+
+    // without socks5 every connection goes to the fixed upstream
+    if let Some((host, upstream_port)) = upstream {
+        let dst = format!("{host}:{upstream_port}");
+        loop {
+            let (conn, peer) = tokio::select! {
+                res = listener.accept() => match res {
+                    Ok(accepted) => accepted,
+                    Err(_) => break
+                },
+                _ = shutdown.recv() => break,
+                // reap finished handlers so the set only holds live connections
+                Some(_) = tasks.join_next(), if !tasks.is_empty() => continue,
+            };
+            let state = state.clone();
+            let host = host.clone();
+            let id = ConnectionId::next();
+            let span = info_span!("connection", %id, %peer, %dst, sni = field::Empty, host = field::Empty, retry = field::Empty);
+            let flow = state.flows.open(peer.ip(), dst.clone());
+            span.record("retry", flow.retries);
+            let params = params.clone().escalate(flow.retries);
+            tasks.spawn(async move {
+                match handle_plain(conn, id, &host, upstream_port, params, &state).await {
+                    Ok(result) => log_result(&result),
+                    Err(err) => error!("{err}"),
+                }
+                drop(flow);
+            }.instrument(span));
+        }
+    } else {
+        let server = Server::new(listener, auth);
+        loop {
+            let (conn, peer) = tokio::select! {
+                res = server.accept() => match res {
+                    Ok(accepted) => accepted,
+                    Err(_) => break
+                },
+                _ = shutdown.recv() => break,
+                Some(_) = tasks.join_next(), if !tasks.is_empty() => continue,
+            };
+            let params = params.clone();
+            let state = state.clone();
+            let id = ConnectionId::next();
+            let span = info_span!("connection", %id, %peer, dst = field::Empty, sni = field::Empty, host = field::Empty, retry = field::Empty);
+            tasks.spawn(async move {
+                match handle(conn, id, params, &state).await {
+                    Ok(Some(result)) => log_result(&result),
+                    Ok(None) => {}
+                    Err(err) => error!("{err}"),
+                }
+            }.instrument(span));
+        }
+    }
+
+    // the listener is closed by now, let the open connections finish. a
+    // second signal aborts them
+    info!(connections = tasks.len(), "shutting down");
+    loop {
+        tokio::select! {
+            res = tasks.join_next() => if res.is_none() {
+                break;
+            },
+            _ = shutdown.recv() => tasks.abort_all(),
+        }
+    }
 
     Ok(())
 }
 
-async fn handle(conn: IncomingConnection<(), NeedAuthenticate>, params: Params) -> Result<(), Error> {
+// receives on every ctrl-c and, on unix, every SIGTERM
+fn shutdown_signal() -> std::io::Result<broadcast::Receiver<()>> {
+    let (tx, rx) = broadcast::channel(1);
+    #[cfg(unix)]
+    let mut terminate = tokio::signal::unix::signal(tokio::signal::unix::SignalKind::terminate())?;
+    tokio::spawn(async move {
+        loop {
+            #[cfg(unix)]
+            tokio::select! {
+                _ = tokio::signal::ctrl_c() => {}
+                _ = terminate.recv() => {}
+            }
+            #[cfg(not(unix))]
+            let _ = tokio::signal::ctrl_c().await;
+            let _ = tx.send(());
+        }
+    });
+    Ok(rx)
+}
+
+fn log_result(result: &DesyncResult) {
+    if metrics::connections_only() {
+        return;
+    }
+    debug!(
+        protocol = ?result.protocol,
+        tlsrec = result.tlsrec_applied,
+        methods = ?result.methods_applied,
+        bytes = result.bytes_written,
+        "desync applied"
+    );
+}
+
+// shared by all connections
+struct State {
+    flows: Arc<FlowTracker>,
+    sni_cache: SniCache,
+    pool: Option<Arc<PreconnectPool>>,
+    limiter: Option<Arc<ConnectionLimiter>>,
+    wire_log: Option<Arc<WireLogFile>>
+}
+
+async fn handle(
+    conn: IncomingConnection<AuthOutput, NeedAuthenticate>,
+    id: ConnectionId,
+    params: Params,
+    state: &State
+) -> Result<Option<DesyncResult>, Error> {
+    let mut metrics = ConnectionMetrics::new(id);
+    let result = handle_socks5(conn, params, state, &mut metrics).await;
+    metrics.error = result.is_err();
+    result
+}
+
+async fn handle_socks5(
+    conn: IncomingConnection<AuthOutput, NeedAuthenticate>,
+    params: Params,
+    state: &State,
+    metrics: &mut ConnectionMetrics
+) -> Result<Option<DesyncResult>, Error> {
     let conn = match conn.authenticate().await {
-        Ok((conn, _)) => conn,
+        Ok((conn, Ok(true))) => conn,
+        Ok((mut conn, Ok(false))) => {
+            warn!("socks5 authentication failed");
+            let _ = conn.close().await;
+            return Ok(None);
+        }
+        Ok((mut conn, Err(err))) => {
+            let _ = conn.close().await;
+            return Err(Error::Io(err.into()));
+        }
         Err((err, mut conn)) => {
             let _ = conn.shutdown().await;
             return Err(err);
@@ -105,15 +493,30 @@ async fn handle(conn: IncomingConnection<(), NeedAuthenticate>, params: Params)
             let _ = conn.close().await;
         }
         Ok(Command::Connect(connect, addr)) => {
-            let target = match addr {
-                Address::DomainAddress(domain, port) => {
-                    let domain = String::from_utf8_lossy(&domain);
-                    TcpStream::connect((domain.as_ref(), port)).await
-                }
-                Address::SocketAddress(addr) => TcpStream::connect(addr).await,
+            Span::current().record("dst", field::display(&addr));
+            // held until the connection ends, so the next one counts as a retry only after this one closed
+            let flow = connect.peer_addr().ok().map(|peer| state.flows.open(peer.ip(), addr.to_string()));
+            let retries = flow.as_ref().map_or(0, |flow| flow.retries);
+            Span::current().record("retry", retries);
+            let params = params.escalate(retries);
+            // connections by ip are annotated with the sni last sent to that address
+            let ip_target = match &addr {
+                Address::SocketAddress(addr) => Some(*addr),
+                Address::DomainAddress(..) => None,
+            };
+            if let Some(sni) = ip_target.and_then(|addr| state.sni_cache.get(&addr)) {
+                Span::current().record("sni", field::display(sni));
+            }
+            let port = match &addr {
+                Address::DomainAddress(_, port) => *port,
+                Address::SocketAddress(addr) => addr.port(),
+            };
+            let target = match acquire_slot(addr.to_string(), state).await {
+                Ok(permit) => connect_target(addr, &params, state).await.map(|target| (target, permit)),
+                Err(err) => Err(err)
             };
             
-            if let Ok(mut target) = target {
+            if let Ok((target, _permit)) = target {
                 let replied = connect
                     .reply(Reply::Succeeded, Address::unspecified())
                     .await;
@@ -126,14 +529,8 @@ async fn handle(conn: IncomingConnection<(), NeedAuthenticate>, params: Params)
                     }
                 };
                 
-                let conn = conn.get_mut();
-                let nodelay = target.nodelay()?;
-
-                target.set_nodelay(true)?;
-                desync_hello_phrase(conn, &mut target, params).await?;
-                target.set_nodelay(nodelay)?;
-
-                copy_bidirectional(conn, &mut target).await?;
+                let result = forward(conn.get_mut(), target, port, ip_target, params, state, metrics).await?;
+                return Ok(Some(result));
             } else {
                 let replied = connect
                     .reply(Reply::HostUnreachable, Address::unspecified())
@@ -156,107 +553,1418 @@ async fn handle(conn: IncomingConnection<(), NeedAuthenticate>, params: Params)
         }
     }
 
-    Ok(())
+    Ok(None)
+}
+
+async fn handle_plain(
+    mut conn: TcpStream,
+    id: ConnectionId,
+    host: &str,
+    port: u16,
+    params: Params,
+    state: &State
+) -> std::io::Result<DesyncResult> {
+    let mut metrics = ConnectionMetrics::new(id);
+    let result = async {
+        let _permit = acquire_slot(format!("{host}:{port}"), state).await?;
+        let target = connect_host(host, port, &params, state).await?;
+        forward(&mut conn, target, port, None, params, state, &mut metrics).await
+    }.await;
+    metrics.error = result.is_err();
+    result
+}
+
+// waits for a free slot with --max-upstream-connections-per-host
+async fn acquire_slot(dst: String, state: &State) -> std::io::Result<Option<HostPermit>> {
+    match &state.limiter {
+        Some(limiter) => limiter.acquire(dst).await.map(Some),
+        None => Ok(None)
+    }
+}
+
+async fn connect_target(addr: Address, params: &Params, state: &State) -> std::io::Result<TcpStream> {
+    match addr {
+        Address::DomainAddress(domain, port) => {
+            connect_host(&String::from_utf8_lossy(&domain), port, params, state).await
+        }
+        Address::SocketAddress(addr) if state.pool.is_some() => {
+            connect_host(&addr.ip().to_string(), addr.port(), params, state).await
+        }
+        Address::SocketAddress(addr) => connect_from(addr, params.connect_options()).await,
+    }
+}
+
+async fn connect_host(host: &str, port: u16, params: &Params, state: &State) -> std::io::Result<TcpStream> {
+    match &state.pool {
+        Some(pool) => pool.connect(host, port).await,
+        None => connect_happy_eyeballs(host, port, params.connect_options()).await
+    }
+}
+
+// desyncs the first client packet to the connected target, then relays until both sides close
+async fn forward(
+    conn: &mut TcpStream,
+    mut target: TcpStream,
+    port: u16,
+    ip_target: Option<SocketAddr>,
+    params: Params,
+    state: &State,
+    metrics: &mut ConnectionMetrics
+) -> std::io::Result<DesyncResult> {
+    conn.set_nodelay(params.nodelay_client)?;
+    target.set_nodelay(params.nodelay_upstream)?;
+    if let Some(size) = params.write_buf_size {
+        platform::set_send_buffer_size(&target, size)?;
+    }
+    if let Some(idle) = params.keep_alive_upstream {
+        // keeps nat bindings alive on long idle websocket or sse connections
+        platform::set_keepalive(&target, idle)?;
+    }
+    if let Some(version) = params.proxy_protocol {
+        let header = version.encode(conn.peer_addr()?, target.peer_addr()?);
+        target.write_all(&header).await?;
+    }
+    let upstream_timeout = params.upstream_timeout;
+    let http_inject_trailer = params.http_inject_trailer.clone();
+    let half_duplex = params.half_duplex;
+    let result = match &state.wire_log {
+        Some(wire_log) => {
+            let mut recorder = WireRecorder::new(&mut target);
+            let result = desync_hello_phrase(conn, &mut recorder, port, params).await?;
+            wire_log.append(metrics.id, &recorder.into_log());
+            result
+        }
+        None => desync_hello_phrase(conn, &mut target, port, params).await?
+    };
+    if let (Some(addr), Some(sni)) = (ip_target, &result.sni) {
+        Span::current().record("sni", field::display(sni));
+        state.sni_cache.insert(addr, sni.clone());
+    }
+
+    metrics.desync_count = result.methods_applied.len() + result.tlsrec_applied as usize;
+    metrics.bytes_out = result.bytes_written as u64;
+
+    let mut target = TimeoutStream::new(target, upstream_timeout);
+    let (bytes_out, bytes_in) = match (http_inject_trailer.filter(|_| result.protocol == Protocol::Http), half_duplex) {
+        (Some(trailer), false) => relay_bidirectional_with(conn, &mut target, trailer_injector(trailer)).await?,
+        (None, false) => relay_bidirectional(conn, &mut target).await?,
+        (Some(trailer), true) => (0, relay_half_duplex(conn, &mut target, trailer_injector(trailer)).await?),
+        (None, true) => (0, relay_half_duplex(conn, &mut target, |_| None).await?)
+    };
+    metrics.bytes_out += bytes_out;
+    metrics.bytes_in = bytes_in;
+    Ok(result)
+}
+
+// a heuristic on reads from the upstream: a response head at the start of a
+// read says whether the following body is chunked, and the last chunk has to
+// arrive within a single read to get the trailer
+fn trailer_injector((name, value): (String, String)) -> impl FnMut(&[u8]) -> Option<Vec<u8>> {
+    let mut chunked = false;
+    move |data| {
+        if data.starts_with(b"HTTP/1.") {
+            chunked = is_chunked_http_response(data);
+        }
+        if !chunked {
+            return None;
+        }
+        let mut data = data.to_vec();
+        inject_http_trailer(&mut data, &name, &value).then(|| {
+            chunked = false;
+            data
+        })
+    }
 }
 
-async fn desync_hello_phrase<'a, R>(
+async fn desync_hello_phrase<'a, R, W>(
     reader: &'a mut R,
-    writer: &'a mut TcpStream,
+    writer: &'a mut W,
+    port: u16,
     params: Params
-) -> std::io::Result<()>
+) -> std::io::Result<DesyncResult>
 where
-    R: AsyncRead + Unpin + ?Sized
+    R: AsyncRead + Unpin + ?Sized,
+    W: DesyncSink
 {
     let mut hello_buf = [0; 9016];
-    let n = reader.read(&mut hello_buf).await?;
+    let mut n = read_with_retries(reader, &mut hello_buf, params.max_read_retries).await?;
+    if let Some(max_wait) = params.tls_hello_max_wait {
+        let reassembly = timeout(max_wait, async {
+            while n < hello_buf.len() && is_partial_tls_record(&hello_buf[..n]) {
+                match read_with_retries(reader, &mut hello_buf[n..], params.max_read_retries).await? {
+                    0 => break,
+                    read => n += read
+                }
+            }
+            Ok::<_, IoError>(())
+        });
+        if let Ok(Err(err)) = reassembly.await {
+            return Err(err);
+        }
+    }
     let buffer = &hello_buf[..n];
-    let is_https = is_tls_hello(buffer).is_some();
-    if is_https | is_http(buffer).is_some() {
-        desync(buffer,
-            params,
-            writer,
-            is_https).await?;
+    let trace_http_host = params.trace_http_host;
+    let inspection = match TlsInspector.try_inspect(buffer) {
+        Ok(inspection) => Some(inspection),
+        // still desynced, only sni flagged positions don't resolve
+        Err(TlsParseError::MissingExtension) => Some(InspectionResult { protocol: "tls".to_owned(), split_hint: None }),
+        // tls that doesn't parse is passed through, not tried as anything else
+        Err(err @ TlsParseError::InvalidHandshake) => {
+            warn!("{err}, passed through without desync");
+            None
+        }
+        Err(_) => {
+            let builtin: [&dyn Inspector; 2] = [&DtlsInspector, &HttpInspector];
+            builtin.into_iter()
+                .chain(params.inspectors.iter().map(|i| i.as_ref()))
+                .find_map(|i| i.inspect(buffer))
+        }
+    };
+    let inspection = inspection.map(|inspection| {
+        let protocol = match inspection.protocol.as_str() {
+            "tls" => Protocol::Tls,
+            "http" => Protocol::Http,
+            _ => Protocol::Custom(inspection.protocol)
+        };
+        (protocol, inspection.split_hint)
+    });
+    let mut result = match inspection {
+        Some((protocol, hint)) if !skip_desync(&protocol, buffer, port, &params) => {
+            desync(buffer,
+                params,
+                writer,
+                protocol,
+                hint).await?
+        }
+        inspection => {
+            writer.write_all(buffer).await?;
+            DesyncResult {
+                methods_applied: Vec::new(),
+                tlsrec_applied: false,
+                protocol: inspection.map_or(Protocol::Other, |(protocol, _)| protocol),
+                bytes_written: buffer.len(),
+                sni: None
+            }
+        }
+    };
+    if result.protocol == Protocol::Tls {
+        if let Ok(hello) = parse_client_hello(buffer) {
+            debug!(
+                sni = hello.sni(),
+                version = hello.legacy_version(),
+                cipher_suites = hello.cipher_suites().count(),
+                extensions = hello.extensions().len(),
+                "tls ClientHello"
+            );
+            result.sni = hello.sni().map(str::to_owned);
+        }
+    }
+    if result.protocol == Protocol::Http && trace_http_host {
+        if let Some(host) = parse_http_request(buffer).and_then(|request| request.header("host")) {
+            let host = String::from_utf8_lossy(host.value);
+            Span::current().record("host", field::display(&host));
+            info!(%host, "http host");
+        }
     }
-    else {
-        writer.write(buffer).await?;
-    } 
-    writer.flush().await
+    writer.flush().await?;
+    Ok(result)
 }
 
-async fn desync<'a>(bytes: &[u8], params: Params, tcp_stream: &mut TcpStream, is_https: bool) -> Result<(), Error> {
+// an empty or interrupted read is tried again `retries` times, 1ms apart,
+// before it is taken as the end of the stream
+async fn read_with_retries<R>(reader: &mut R, buf: &mut [u8], retries: u32) -> std::io::Result<usize>
+where
+    R: AsyncRead + Unpin + ?Sized
+{
+    for attempt in 0..=retries {
+        if attempt > 0 {
+            sleep(Duration::from_millis(1)).await;
+        }
+        match reader.read(buf).await {
+            Ok(0) => {}
+            Ok(n) => return Ok(n),
+            Err(err) if matches!(err.kind(), ErrorKind::Interrupted | ErrorKind::WouldBlock) => {}
+            Err(err) => return Err(err)
+        }
+    }
+    Ok(0)
+}
+
+const IPV6_HEADER_LEN: usize = 40;
+
+// desynced with --auto-detect-port
+const DESYNC_PORTS: [u16; 5] = [80, 443, 8080, 8443, 8888];
+
+fn skip_desync(protocol: &Protocol, buffer: &[u8], port: u16, params: &Params) -> bool {
+    params.skip_ports.contains(&port)
+        || params.desync_ports.as_ref().is_some_and(|ports| !ports.contains(&port))
+        || *protocol == Protocol::Tls && params.skip_desync_on_resumption && is_tls_session_ticket(buffer)
+        || *protocol == Protocol::Tls && params.skip_desync_on_ech && has_ech_extension(buffer)
+        || params.sni_list.as_ref().is_some_and(|list| {
+            *protocol != Protocol::Tls || !parse_client_hello(buffer).ok().and_then(|hello| hello.sni()).is_some_and(|sni| list.contains(sni))
+        })
+}
+
+async fn desync<S: DesyncSink>(
+    bytes: &[u8],
+    params: Params,
+    tcp_stream: &mut S,
+    protocol: Protocol,
+    mut hint: Option<usize>
+) -> Result<DesyncResult, Error> {
     let mut buffer = Vec::with_capacity(bytes.len());
     bytes.clone_into(&mut buffer);
+    let mut result = DesyncResult {
+        methods_applied: Vec::new(),
+        tlsrec_applied: false,
+        protocol: protocol.clone(),
+        bytes_written: 0,
+        sni: None
+    };
+
+    if let Some((from, to)) = &params.rewrite_host {
+        if protocol == Protocol::Http {
+            rewrite_http_host(&mut buffer, from, to);
+        }
+    }
+
+    if protocol == Protocol::Http {
+        for name in &params.http_remove_headers {
+            let Some(removed) = remove_http_header(&mut buffer, name) else {
+                continue;
+            };
+            // a removed Host header leaves the request target, like the
+            // inspector falls back to without one
+            hint = hint.and_then(|h| match h {
+                h if h < removed.start => Some(h),
+                h if h >= removed.end => Some(h - removed.len()),
+                _ => url_start_offset(&buffer)
+            });
+        }
+    }
+
+    if params.http_version_downgrade && protocol == Protocol::Http {
+        downgrade_http_version(&mut buffer);
+    }
+
+    if let Some(keyword) = &params.http_uri_encode {
+        let len = buffer.len();
+        if protocol == Protocol::Http && uri_encode_keyword(&mut buffer, keyword) {
+            // the request line grew, everything after the target moved with it
+            let path_offset = url_start_offset(&buffer).unwrap_or(0);
+            hint = hint.map(|h| if h > path_offset { h + buffer.len() - len } else { h });
+        }
+    }
+
+    let rule = params.strategy.iter().find(|rule| rule.predicate.matches(&protocol));
+    let methods = match rule {
+        Some(rule) => &rule.methods,
+        None => &params.methods
+    };
+
+    if let Some(sni) = &params.upstream_sni {
+        if protocol == Protocol::Tls && !replace_sni(&mut buffer, sni) {
+            warn!("upstream sni not applied: unsupported ClientHello layout");
+        }
+    }
 
-    if let Some(part) = &params.tlsrec {
-        if is_https && part.pos < buffer.len() {
-            part_tls(&mut buffer, part.pos);
+    if let Some(len) = params.fake_sni_length {
+        if protocol == Protocol::Tls && !pad_sni_to_length(&mut buffer, len) {
+            warn!(len, "sni not padded: longer than the target length or unsupported ClientHello layout");
         }
     }
 
+    let (mut cipher_end, mut sni_end) = match protocol {
+        Protocol::Tls => (cipher_suites_end_offset(&buffer), sni_end_offset(&buffer)),
+        _ => (None, None)
+    };
+    let mut boundary_splits: Vec<Method> = match protocol {
+        Protocol::Tls if params.split_tls_extensions => tls_extension_offsets(&buffer),
+        Protocol::Http if params.split_http_headers => http_header_offsets(&buffer),
+        _ => Vec::new()
+    }.into_iter().map(|pos| Method::Split(Part::absolute(pos))).collect();
+
+    // a strategy rule replaces the random position like any other cli tlsrec
+    let tlsrec_pos = match (rule, params.tlsrec.as_ref()) {
+        (Some(rule), _) => rule.tlsrec.as_ref().and_then(|part| resolve_tlsrec_pos(part, hint)),
+        (None, Some(TlsrecStrategy::Fixed(part))) => resolve_tlsrec_pos(part, hint),
+        (None, Some(&TlsrecStrategy::Random { min, max })) => {
+            // a position past the record payload in the buffer skips tlsrec
+            // for this connection
+            let pos = rand::thread_rng().gen_range(min..max);
+            (pos < buffer.len().saturating_sub(5)).then_some(pos)
+        }
+        (None, None) => None
+    };
+    if let Some(pos) = tlsrec_pos {
+        if protocol == Protocol::Tls && pos < buffer.len() && buffer.len() >= params.tlsrec_min_size {
+            if buffer[0] != 0x16 {
+                warn!("tlsrec skipped: unexpected tls record type {:#04x}", buffer[0]);
+            } else if let Err(err) = part_tls(&mut buffer, pos) {
+                warn!(pos, "tlsrec skipped: {err}");
+            } else {
+                result.tlsrec_applied = true;
+                // part_tls inserts a 5 byte record header at 5 + pos
+                let shift = |h: usize| if h >= 5 + pos { h + 5 } else { h };
+                hint = hint.map(shift);
+                cipher_end = cipher_end.map(shift);
+                sni_end = sni_end.map(shift);
+                for split in &mut boundary_splits {
+                    let part = split.part_mut();
+                    part.pos = shift(part.pos);
+                }
+            }
+        }
+    }
+
+    // a heuristic: the payload byte at the length of a bare ipv6 header, for
+    // dpi that takes tcp payload offsets for ip packet offsets
+    let ipv6_split = params.split_ipv6 && tcp_stream.peer_addr().is_ok_and(|addr| addr.is_ipv6());
+    let even: Vec<Method> = params.split_count
+        .map(|count| even_splits(buffer.len(), count))
+        .unwrap_or_default()
+        .into_iter()
+        .chain(ipv6_split.then_some(IPV6_HEADER_LEN))
+        .map(|pos| Method::Split(Part::absolute(pos)))
+        .chain(boundary_splits)
+        .collect();
+    let mut resolved: Vec<(usize, &Method)> = methods.iter()
+        .chain(&even)
+        .filter_map(|m| resolve_pos(m, &buffer, &protocol, hint, cipher_end, sni_end).map(|pos| (pos, m)))
+        .map(|(pos, m)| match params.position_parity {
+            Some(Parity::Even) => (pos & !1, m),
+            Some(Parity::Odd) => (pos | 1, m),
+            None => (pos, m)
+        })
+        .collect();
+    resolved.sort_by_key(|&(pos, _)| pos);
+
+    // with --prefer-split-over-disorder, a socket that refuses a ttl of 1
+    // gets splits at the disorder positions instead
+    let disorder_blocked = params.fallback_to_split
+        && resolved.iter().any(|(_, m)| matches!(m, Method::Disorder(_)))
+        && !can_lower_ttl(tcp_stream)?;
+    if disorder_blocked && !metrics::connections_only() {
+        debug!("ttl can't be lowered, disorder sent as split");
+    }
+
     let mut offset = 0;
-    for method in &params.methods {
-        let pos = method_part(&method).pos;
-        if pos <= offset || pos >= buffer.len() {
+    let mut segments = 0;
+    for (pos, method) in resolved {
+        if pos >= buffer.len() {
             break;
         }
+        if segments >= params.segment_count_limit {
+            if !metrics::connections_only() {
+                debug!(limit = params.segment_count_limit, "segment count limit reached, remaining methods skipped");
+            }
+            break;
+        }
+        // generated splits can land on the same position
+        if pos <= offset {
+            continue;
+        }
+        // where the next segment starts, an oob segment can take more bytes
+        let mut end = pos;
         match method {
-            Method::Split(part) => {
-                tcp_stream.write_all(&buffer[offset..part.pos]).await?;
+            Method::Oob(_, size) if pos + size > buffer.len() && platform::check_oob_support() => {
+                warn!(pos, size, "oob skipped: not enough data after the position");
+                continue;
+            }
+            _ => tcp_stream.begin_method(Some(method))
+        }
+        // how many segments the method sent
+        segments += match method {
+            Method::Split(_) | Method::HttpFragmentUrl(_) | Method::FragmentCipher(_) => {
+                // uncorking pushes out the whole fragment as one segment
+                if params.tcp_cork {
+                    tcp_stream.set_cork(true)?;
+                }
+                tcp_stream.write_all(&buffer[offset..pos]).await?;
+                if params.tcp_cork {
+                    tcp_stream.set_cork(false)?;
+                }
+                tcp_stream.flush().await?;
+                result.bytes_written += pos - offset;
+                1
+            }
+            Method::Disorder(_) if disorder_blocked => {
+                tcp_stream.write_all(&buffer[offset..pos]).await?;
                 tcp_stream.flush().await?;
+                result.bytes_written += pos - offset;
+                1
             }
-            Method::Disorder(part) => {
+            Method::Disorder(_) => {
                 let ttl = tcp_stream.ttl()?;
                 tcp_stream.set_ttl(1)?;
-                tcp_stream.write_all(&buffer[offset..part.pos]).await?;
+                tcp_stream.write_all(&buffer[offset..pos]).await?;
                 tcp_stream.flush().await?;
                 tcp_stream.set_ttl(ttl)?;
+                result.bytes_written += pos - offset;
+                1
             }
-            Method::Oob(part) => {
-                let sock = SockRef::from(&tcp_stream);
-                let ch = buffer[part.pos];
-                buffer[part.pos] = b'a';
-                sock.send_out_of_band(&buffer[offset..part.pos + 1])?;
-                buffer[part.pos] = ch;
+            Method::Oob(..) | Method::OobUrgent(_) if !platform::check_oob_support() => {
+                if params.disable_oob_fallback {
+                    return Err(IoError::new(ErrorKind::Unsupported, "tcp urgent data unsupported, oob fallback disabled").into());
+                }
+                tcp_stream.write_all(&buffer[offset..pos]).await?;
+                tcp_stream.flush().await?;
+                result.bytes_written += pos - offset;
+                1
             }
-        }
-        offset = pos;
+            Method::Oob(_, size) => {
+                // the segment carries `size` bytes past the position, the
+                // last one is the urgent byte and is swapped for a fake
+                end = pos + size - 1;
+                let ch = buffer[end];
+                buffer[end] = b'a';
+                result.bytes_written += tcp_stream.send_oob(&buffer[offset..end + 1])?;
+                buffer[end] = ch;
+                1
+            }
+            Method::OobUrgent(_) => {
+                // the urgent notification gets a segment of its own, its
+                // only byte is the one a receiver takes out of the stream
+                tcp_stream.write_all(&buffer[offset..pos]).await?;
+                tcp_stream.flush().await?;
+                result.bytes_written += pos - offset + tcp_stream.send_oob(b"a")?;
+                2
+            }
+        };
+        result.methods_applied.push((method.clone(), pos));
+        offset = end;
     }
     if offset < buffer.len() {
+        tcp_stream.begin_method(None);
         tcp_stream.write_all(&buffer[offset..]).await?;
+        result.bytes_written += buffer.len() - offset;
     }
-    Ok(())
+    Ok(result)
+}
+
+fn can_lower_ttl<S: DesyncSink>(tcp_stream: &S) -> std::io::Result<bool> {
+    let ttl = tcp_stream.ttl()?;
+    match tcp_stream.set_ttl(1) {
+        Ok(()) => tcp_stream.set_ttl(ttl).map(|()| true),
+        Err(err) if err.kind() == ErrorKind::PermissionDenied => Ok(false),
+        Err(err) => Err(err)
+    }
+}
+
+#[derive(Debug)]
+struct DesyncResult {
+    methods_applied: Vec<(Method, usize)>,
+    tlsrec_applied: bool,
+    protocol: Protocol,
+    bytes_written: usize,
+    sni: Option<String>
+}
+
+#[derive(Clone, Debug, PartialEq)]
+enum Protocol {
+    Tls,
+    Http,
+    Custom(String),
+    Other
 }
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum TlsrecStrategy {
+    Fixed(Part),
+    // a new position in min..max for every connection
+    Random { min: usize, max: usize }
+}
+
+// some dpi reassembles in two byte steps and only looks at aligned offsets
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+enum Parity {
+    Even,
+    Odd
+}
+
+// `--config-dump` prints it as json, custom inspectors are code and left out
+#[derive(Clone, Debug, Serialize, Deserialize)]
 struct Params {
-    tlsrec: Option<Part>,
-    methods: Vec<Method>
+    tlsrec: Option<TlsrecStrategy>,
+    tlsrec_min_size: usize,
+    rewrite_host: Option<(String, String)>,
+    disable_oob_fallback: bool,
+    fallback_to_split: bool,
+    http_remove_headers: Vec<String>,
+    http_uri_encode: Option<String>,
+    http_inject_trailer: Option<(String, String)>,
+    http_version_downgrade: bool,
+    upstream_sni: Option<String>,
+    fake_sni_length: Option<usize>,
+    nodelay_upstream: bool,
+    nodelay_client: bool,
+    write_buf_size: Option<usize>,
+    tcp_cork: bool,
+    half_duplex: bool,
+    trace_http_host: bool,
+    keep_alive_upstream: Option<Duration>,
+    upstream_timeout: Option<Duration>,
+    tls_hello_max_wait: Option<Duration>,
+    max_read_retries: u32,
+    skip_desync_on_resumption: bool,
+    skip_desync_on_ech: bool,
+    skip_ports: HashSet<u16>,
+    // None desyncs every port
+    desync_ports: Option<HashSet<u16>>,
+    split_count: Option<usize>,
+    position_parity: Option<Parity>,
+    segment_count_limit: usize,
+    split_tls_extensions: bool,
+    split_http_headers: bool,
+    split_ipv6: bool,
+    strategy: Vec<StrategyRule>,
+    ip_preference: IpPreference,
+    upstream_local_ip: Option<IpAddr>,
+    tcp_window_scale: Option<u8>,
+    tcp_mss: Option<u32>,
+    proxy_protocol: Option<ProxyProtocol>,
+    #[serde(skip)]
+    inspectors: Vec<Arc<dyn Inspector>>,
+    methods: Vec<Method>,
+    // used instead of these params when a client comes back after its flow
+    // closed, see --split-on-retransmit
+    retry_params: Option<Box<Params>>,
+    // loaded from --sni-list-file at startup, None desyncs every server name
+    #[serde(skip)]
+    sni_list: Option<Arc<SniList>>
+}
+
+impl Params {
+    // orders methods by position, methods at the same position by kind, so
+    // the one applied there doesn't depend on where it was configured. a
+    // method given twice, e.g. by a flag and a --method spec, is kept once
+    fn normalize(&mut self) {
+        let key = |m: &Method| (m.part().pos, m.ordinal());
+        let dedup = |methods: &mut Vec<Method>| {
+            let mut seen = HashSet::new();
+            methods.retain(|m| seen.insert(m.clone()));
+        };
+        dedup(&mut self.methods);
+        self.methods.sort_by_key(key);
+        for rule in &mut self.strategy {
+            dedup(&mut rule.methods);
+            rule.methods.sort_by_key(key);
+        }
+    }
+
+    // one more split, below every configured position, for when the last
+    // attempt was likely blocked
+    fn with_retry_split(&self) -> Params {
+        let mut retry = self.clone();
+        let split = Method::Split(Part::absolute(1));
+        retry.methods.push(split.clone());
+        for rule in &mut retry.strategy {
+            rule.methods.push(split.clone());
+        }
+        retry.normalize();
+        retry
+    }
+
+    fn set_sni_list(&mut self, list: Arc<SniList>) {
+        if let Some(retry) = &mut self.retry_params {
+            retry.set_sni_list(list.clone());
+        }
+        self.sni_list = Some(list);
+    }
+
+    fn escalate(self, retries: u32) -> Params {
+        match self.retry_params {
+            Some(retry) if retries > 0 => *retry,
+            _ => self
+        }
+    }
+
+    fn connect_options(&self) -> ConnectOptions {
+        ConnectOptions {
+            ip_preference: self.ip_preference,
+            local_ip: self.upstream_local_ip,
+            window_scale: self.tcp_window_scale,
+            mss: self.tcp_mss
+        }
+    }
+}
+
+// comma separated `--method` specs, e.g. `disorder:1,split:40,oob:-1:sni`
+fn parse_split_composite(s: &str) -> Result<Vec<Method>, String> {
+    s.split(',')
+        .map(|spec| Method::try_from(spec.trim()).map_err(|e| e.to_string()))
+        .collect()
+}
+
+fn parse_http_trailer(s: &str) -> Result<(String, String), String> {
+    let trailer = s.split_once(':').map(|(name, value)| (name.trim(), value.trim()));
+    match trailer {
+        Some((name, value)) if !name.is_empty()
+            && name.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-')
+            && !value.bytes().any(|b| b.is_ascii_control()) => Ok((name.to_owned(), value.to_owned())),
+        _ => Err(format!("expected <name>:<value>, got `{s}`"))
+    }
 }
 
-#[derive(Clone, Debug)]
+fn parse_hostname(s: &str) -> Result<String, String> {
+    if !s.is_empty() && s.len() <= 253 && s.bytes().all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'.') {
+        Ok(s.to_owned())
+    } else {
+        Err(format!("invalid hostname `{s}`"))
+    }
+}
+
+fn parse_range(s: &str) -> Result<(usize, usize), String> {
+    let range = s.split_once('-').and_then(|(min, max)| Some((min.parse().ok()?, max.parse().ok()?)));
+    match range {
+        Some((min, max)) if min < max => Ok((min, max)),
+        _ => Err(format!("expected <min>-<max> with min < max, got `{s}`"))
+    }
+}
+
+fn parse_host_rewrite(s: &str) -> Result<(String, String), String> {
+    match s.split_once('=') {
+        Some((from, to)) if !from.is_empty() && !to.is_empty() => Ok((from.to_owned(), to.to_owned())),
+        _ => Err(format!("expected <from>=<to>, got `{s}`"))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+#[allow(clippy::enum_variant_names)]
 enum Flag {
     OffsetSni,
-    OffsetHost
+    // just past the last byte of the server name
+    OffsetSniEnd,
+    OffsetHost,
+    OffsetCipher,
+    OffsetHeader(String)
 }
 
-#[derive(Clone, Debug)]
+impl Flag {
+    // `sni`, `sni-end`, `host`, `cipher` or `header=<name>`, the counterpart of `Display`
+    fn parse(s: &str) -> Option<Self> {
+        match s {
+            "sni" => Some(Flag::OffsetSni),
+            "sni-end" => Some(Flag::OffsetSniEnd),
+            "host" => Some(Flag::OffsetHost),
+            "cipher" => Some(Flag::OffsetCipher),
+            _ => s.strip_prefix("header=")
+                .filter(|name| !name.is_empty())
+                .map(|name| Flag::OffsetHeader(name.to_owned()))
+        }
+    }
+}
+
+impl std::fmt::Display for Flag {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Flag::OffsetSni => f.write_str("sni"),
+            Flag::OffsetSniEnd => f.write_str("sni-end"),
+            Flag::OffsetHost => f.write_str("host"),
+            Flag::OffsetCipher => f.write_str("cipher"),
+            Flag::OffsetHeader(name) => write!(f, "header={name}")
+        }
+    }
+}
+
+// named like on the command line, `{ "disorder": { "pos": 1 } }`
+#[derive(Clone, Debug, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
 enum Method {
     Split(Part),
     Disorder(Part),
-    Oob(Part)
+    // the size is how many bytes from the position on go into the oob
+    // segment, see `OobRepr` for the config file form
+    #[serde(serialize_with = "serialize_oob", deserialize_with = "deserialize_oob")]
+    Oob(Part, usize),
+    #[serde(rename = "oob-window")]
+    OobUrgent(Part),
+    #[serde(rename = "http-url-split")]
+    HttpFragmentUrl(Part),
+    // a split counted from the end of the tls cipher suite list
+    FragmentCipher(Part)
+}
+
+// parses `<method>:<pos>[:<flag>]`, e.g. `split:40`, `disorder:1:sni`, `oob:-1:sni`, `split:0:header=Host`.
+// `fragment-cipher` without a flag counts from the cipher suite list. oob
+// takes its size last, e.g. `oob:3:size=2` or `oob:-1:sni:size=2`
+impl TryFrom<&str> for Method {
+    type Error = ParseMethodError;
+
+    fn try_from(s: &str) -> Result<Self, Self::Error> {
+        let mut fields = s.split(':');
+        let kind = fields.next().unwrap_or_default();
+        let pos = fields.next().ok_or_else(|| ParseMethodError::MissingPosition(s.to_owned()))?;
+        let invalid = || ParseMethodError::InvalidPosition(pos.to_owned());
+        // a negative position counts back from the flag, e.g. `oob:-1:sni`
+        let (pos, offset) = match pos.strip_prefix('-') {
+            Some(back) => (0, back.parse::<i32>().map_err(|_| invalid())?.checked_neg().ok_or_else(invalid)?),
+            None => (pos.parse().map_err(|_| invalid())?, 0)
+        };
+        let (flag, size) = match fields.next() {
+            Some(field) if field.starts_with("size=") => (None, Some(field)),
+            flag => (flag, fields.next())
+        };
+        let size = match size {
+            None => 1,
+            Some(field) if kind == "oob" => match field.strip_prefix("size=").and_then(|size| size.parse().ok()) {
+                Some(size) if size > 0 => size,
+                _ => return Err(ParseMethodError::InvalidSize(field.to_owned()))
+            },
+            Some(_) => return Err(ParseMethodError::TrailingInput(s.to_owned()))
+        };
+        let part = match flag {
+            None if kind == "fragment-cipher" => Part::with_cipher_flag(pos),
+            None if offset != 0 => return Err(invalid()),
+            None => Part::absolute(pos),
+            Some("sni") => Part::with_sni_flag(pos),
+            Some("sni-end") => Part::with_sni_end_flag(pos),
+            Some("cipher") => Part::with_cipher_flag(pos),
+            Some("host") => Part::with_host_flag(pos),
+            Some(flag) if flag.starts_with("header=") && flag.len() > 7 => {
+                Part::with_header_flag(pos, flag[7..].to_owned())
+            }
+            Some(flag) => return Err(ParseMethodError::UnknownFlag(flag.to_owned()))
+        };
+        let part = part.with_negative_offset(offset);
+        if fields.next().is_some() {
+            return Err(ParseMethodError::TrailingInput(s.to_owned()));
+        }
+        match kind {
+            "split" => Ok(Method::Split(part)),
+            "disorder" => Ok(Method::Disorder(part)),
+            "oob" => Ok(Method::Oob(part, size)),
+            "oob-window" => Ok(Method::OobUrgent(part)),
+            "http-url-split" => Ok(Method::HttpFragmentUrl(part)),
+            "fragment-cipher" => Ok(Method::FragmentCipher(part)),
+            _ => Err(ParseMethodError::UnknownMethod(kind.to_owned()))
+        }
+    }
+}
+
+// inverse of `TryFrom<&str>`
+impl std::fmt::Display for Method {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        let kind = match self {
+            Method::Split(_) => "split",
+            Method::Disorder(_) => "disorder",
+            Method::Oob(..) => "oob",
+            Method::OobUrgent(_) => "oob-window",
+            Method::HttpFragmentUrl(_) => "http-url-split",
+            Method::FragmentCipher(_) => "fragment-cipher"
+        };
+        let part = self.part();
+        write!(f, "{kind}:{}", part.pos as i64 + part.offset as i64)?;
+        if let Some(flag) = &part.flag {
+            write!(f, ":{flag}")?;
+        }
+        match self {
+            Method::Oob(_, size) if *size != 1 => write!(f, ":size={size}"),
+            _ => Ok(())
+        }
+    }
+}
+
+#[derive(Debug)]
+enum ParseMethodError {
+    UnknownMethod(String),
+    MissingPosition(String),
+    InvalidPosition(String),
+    UnknownFlag(String),
+    InvalidSize(String),
+    TrailingInput(String)
+}
+
+impl std::fmt::Display for ParseMethodError {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            ParseMethodError::UnknownMethod(kind) => write!(f, "unknown method `{kind}`"),
+            ParseMethodError::MissingPosition(s) => write!(f, "missing position in `{s}`"),
+            ParseMethodError::InvalidPosition(pos) => write!(f, "invalid position `{pos}`"),
+            ParseMethodError::UnknownFlag(flag) => write!(f, "unknown flag `{flag}`"),
+            ParseMethodError::InvalidSize(size) => write!(f, "invalid oob size `{size}`"),
+            ParseMethodError::TrailingInput(s) => write!(f, "unexpected trailing input in `{s}`")
+        }
+    }
+}
+
+impl std::error::Error for ParseMethodError {}
+
+// custom protocols resolve both sni and host flags against their inspector's split hint
+fn resolve_pos(
+    method: &Method,
+    buffer: &[u8],
+    protocol: &Protocol,
+    hint: Option<usize>,
+    cipher_end: Option<usize>,
+    sni_end: Option<usize>
+) -> Option<usize> {
+    let part = method.part();
+    if let Method::HttpFragmentUrl(_) = method {
+        // counted from the start of the request target, flags don't apply
+        return match protocol {
+            Protocol::Http => url_start_offset(buffer).and_then(|base| part.apply_offset(base)),
+            _ => None
+        };
+    }
+    let base = match &part.flag {
+        None => 0,
+        Some(Flag::OffsetSni) if *protocol != Protocol::Http => hint?,
+        Some(Flag::OffsetHost) if *protocol != Protocol::Tls => hint?,
+        Some(Flag::OffsetSniEnd) => sni_end?,
+        Some(Flag::OffsetCipher) => cipher_end?,
+        Some(Flag::OffsetHeader(name)) => find_http_header(buffer, name)?,
+        Some(_) => return None
+    };
+    part.apply_offset(base)
 }
 
-fn method_part(m: &Method) -> &Part {
-    match m {
-        Method::Split(p)
-        | Method::Disorder(p)
-        | Method::Oob(p)
-        => p
+// tlsrec positions count from the start of the record payload
+fn resolve_tlsrec_pos(part: &Part, hint: Option<usize>) -> Option<usize> {
+    match part.flag {
+        None => part.apply_offset(0),
+        Some(Flag::OffsetSni) => part.apply_offset(hint?.checked_sub(5)?),
+        Some(_) => None
     }
 }
 
-#[derive(Clone, Debug)]
+// positions of the count - 1 splits that cut the buffer into count equal parts
+fn even_splits(buf_len: usize, count: usize) -> Vec<usize> {
+    let mut positions: Vec<usize> = (1..count)
+        .map(|i| i * buf_len / count)
+        .filter(|&pos| pos > 0)
+        .collect();
+    positions.dedup();
+    positions
+}
+
+impl Method {
+    fn ordinal(&self) -> u8 {
+        match self {
+            Method::Split(_) => 0,
+            Method::Disorder(_) => 1,
+            Method::Oob(..) => 2,
+            Method::OobUrgent(_) => 3,
+            Method::HttpFragmentUrl(_) => 4,
+            Method::FragmentCipher(_) => 5
+        }
+    }
+
+    fn part(&self) -> &Part {
+        match self {
+            Method::Split(p)
+            | Method::Disorder(p)
+            | Method::Oob(p, _)
+            | Method::OobUrgent(p)
+            | Method::HttpFragmentUrl(p)
+            | Method::FragmentCipher(p)
+            => p
+        }
+    }
+
+    fn part_mut(&mut self) -> &mut Part {
+        match self {
+            Method::Split(p)
+            | Method::Disorder(p)
+            | Method::Oob(p, _)
+            | Method::OobUrgent(p)
+            | Method::HttpFragmentUrl(p)
+            | Method::FragmentCipher(p)
+            => p
+        }
+    }
+}
+
+// `offset` is added after the flag is resolved, so a position can point
+// before the sni or host
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 struct Part {
     pos: usize,
+    offset: i32,
     flag: Option<Flag>
 }
+
+impl Part {
+    fn absolute(pos: usize) -> Self {
+        Part { pos, offset: 0, flag: None }
+    }
+
+    fn with_sni_flag(pos: usize) -> Self {
+        Part { pos, offset: 0, flag: Some(Flag::OffsetSni) }
+    }
+
+    fn with_sni_end_flag(pos: usize) -> Self {
+        Part { pos, offset: 0, flag: Some(Flag::OffsetSniEnd) }
+    }
+
+    fn with_host_flag(pos: usize) -> Self {
+        Part { pos, offset: 0, flag: Some(Flag::OffsetHost) }
+    }
+
+    fn with_cipher_flag(pos: usize) -> Self {
+        Part { pos, offset: 0, flag: Some(Flag::OffsetCipher) }
+    }
+
+    fn with_header_flag(pos: usize, name: String) -> Self {
+        Part { pos, offset: 0, flag: Some(Flag::OffsetHeader(name)) }
+    }
+
+    fn with_negative_offset(self, offset: i32) -> Self {
+        Part { offset, ..self }
+    }
+
+    // None when the offset moves the position to or before the start
+    fn apply_offset(&self, base: usize) -> Option<usize> {
+        (base + self.pos).checked_add_signed(self.offset as isize).filter(|&pos| pos > 0)
+    }
+}
+
+// `{ "pos": 40, "flag": "sni" }` in config files, a zero offset and a
+// missing flag are left out
+#[derive(Serialize, Deserialize)]
+struct PartRepr {
+    pos: usize,
+    #[serde(default, skip_serializing_if = "is_zero")]
+    offset: i32,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    flag: Option<String>
+}
+
+fn is_zero(offset: &i32) -> bool {
+    *offset == 0
+}
+
+impl Serialize for Part {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        PartRepr { pos: self.pos, offset: self.offset, flag: self.flag.as_ref().map(Flag::to_string) }
+            .serialize(serializer)
+    }
+}
+
+impl<'de> Deserialize<'de> for Part {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let PartRepr { pos, offset, flag } = PartRepr::deserialize(deserializer)?;
+        let flag = match flag {
+            None => None,
+            Some(flag) => Some(Flag::parse(&flag).ok_or_else(|| serde::de::Error::custom(format!("unknown flag `{flag}`")))?)
+        };
+        Ok(Part { pos, offset, flag })
+    }
+}
+
+// `{ "oob": { "pos": 3, "size": 2 } }`, a size of 1 is left out
+#[derive(Serialize, Deserialize)]
+struct OobRepr {
+    #[serde(flatten)]
+    part: Part,
+    #[serde(default = "default_oob_size", skip_serializing_if = "is_default_oob_size")]
+    size: usize
+}
+
+fn default_oob_size() -> usize {
+    1
+}
+
+fn is_default_oob_size(size: &usize) -> bool {
+    *size == 1
+}
+
+fn serialize_oob<S: serde::Serializer>(part: &Part, size: &usize, serializer: S) -> Result<S::Ok, S::Error> {
+    OobRepr { part: part.clone(), size: *size }.serialize(serializer)
+}
+
+fn deserialize_oob<'de, D: serde::Deserializer<'de>>(deserializer: D) -> Result<(Part, usize), D::Error> {
+    match OobRepr::deserialize(deserializer)? {
+        OobRepr { size: 0, .. } => Err(serde::de::Error::custom("oob size must be at least 1")),
+        OobRepr { part, size } => Ok((part, size))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{benchmark::synthetic_client_hello, mock::MockTcpStream};
+
+    const REQUEST: &[u8] = b"GET /path HTTP/1.1\r\nUser-Agent: test\r\nHost: example.com\r\n\r\n";
+
+    fn params(methods: Vec<Method>) -> Params {
+        let mut params = ConfigBuilder::default().build().unwrap().params;
+        params.methods = methods;
+        params
+    }
+
+    async fn run(bytes: &[u8], params: Params, protocol: Protocol, hint: Option<usize>) -> (DesyncResult, MockTcpStream) {
+        run_with(MockTcpStream::new(), bytes, params, protocol, hint).await
+    }
+
+    async fn run_with(mut sink: MockTcpStream, bytes: &[u8], params: Params, protocol: Protocol, hint: Option<usize>) -> (DesyncResult, MockTcpStream) {
+        let result = desync(bytes, params, &mut sink, protocol, hint).await.unwrap();
+        (result, sink)
+    }
+
+    // (bytes, ttl, oob) of every segment
+    fn segments(sink: &MockTcpStream) -> Vec<(String, u32, bool)> {
+        sink.segments().iter()
+            .map(|s| (String::from_utf8_lossy(&s.bytes).into_owned(), s.ttl, s.oob))
+            .collect()
+    }
+
+    fn expected(segments: &[(&str, u32, bool)]) -> Vec<(String, u32, bool)> {
+        segments.iter().map(|&(bytes, ttl, oob)| (bytes.to_owned(), ttl, oob)).collect()
+    }
+
+    #[tokio::test]
+    async fn split_sends_two_segments_at_the_normal_ttl() {
+        let (result, sink) = run(b"hello world", params(vec![Method::Split(Part::absolute(3))]), Protocol::Other, None).await;
+        assert_eq!(segments(&sink), expected(&[("hel", 64, false), ("lo world", 64, false)]));
+        assert!(sink.ttl_changes().is_empty());
+        assert_eq!(result.bytes_written, 11);
+    }
+
+    #[tokio::test]
+    async fn disorder_sends_the_first_segment_at_ttl_1() {
+        let (_, sink) = run(b"hello world", params(vec![Method::Disorder(Part::absolute(3))]), Protocol::Other, None).await;
+        assert_eq!(segments(&sink), expected(&[("hel", 1, false), ("lo world", 64, false)]));
+        assert_eq!(sink.ttl_changes(), [1, 64]);
+    }
+
+    #[tokio::test]
+    async fn disorder_falls_back_to_split_when_the_ttl_is_refused() {
+        let mut params = params(vec![Method::Disorder(Part::absolute(3))]);
+        params.fallback_to_split = true;
+        let (_, sink) = run_with(MockTcpStream::refusing_low_ttl(), b"hello world", params, Protocol::Other, None).await;
+        assert_eq!(segments(&sink), expected(&[("hel", 64, false), ("lo world", 64, false)]));
+        assert!(sink.ttl_changes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn oob_swaps_the_urgent_byte_for_a_fake() {
+        if !platform::check_oob_support() {
+            return;
+        }
+        let (_, sink) = run(b"hello world", params(vec![Method::Oob(Part::absolute(3), 1)]), Protocol::Other, None).await;
+        assert_eq!(segments(&sink), expected(&[("hela", 64, true), ("lo world", 64, false)]));
+        assert_eq!(sink.received(), b"hello world");
+        assert!(sink.ttl_changes().is_empty());
+    }
+
+    #[tokio::test]
+    async fn oob_window_sends_the_fake_urgent_byte_alone() {
+        if !platform::check_oob_support() {
+            return;
+        }
+        let (_, sink) = run(b"hello world", params(vec![Method::OobUrgent(Part::absolute(3))]), Protocol::Other, None).await;
+        assert_eq!(segments(&sink), expected(&[("hel", 64, false), ("a", 64, true), ("lo world", 64, false)]));
+        assert_eq!(sink.received(), b"hello world");
+    }
+
+    #[tokio::test]
+    async fn http_url_split_counts_from_the_request_target() {
+        let (_, sink) = run(REQUEST, params(vec![Method::HttpFragmentUrl(Part::absolute(2))]), Protocol::Http, None).await;
+        let segments = segments(&sink);
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0], expected(&[("GET /p", 64, false)])[0]);
+        assert_eq!(sink.received(), REQUEST);
+    }
+
+    #[tokio::test]
+    async fn fragment_cipher_splits_after_the_cipher_suites() {
+        let hello = synthetic_client_hello("example.com", 512);
+        let end = cipher_suites_end_offset(&hello).unwrap();
+        let (_, sink) = run(&hello, params(vec![Method::FragmentCipher(Part::with_cipher_flag(0))]), Protocol::Tls, None).await;
+        let segments = sink.segments();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].bytes, hello[..end]);
+        assert_eq!(segments[1].bytes, hello[end..]);
+    }
+
+    #[tokio::test]
+    async fn sni_flag_counts_from_the_hint() {
+        let hello = synthetic_client_hello("example.com", 512);
+        let sni = parse_client_hello(&hello).unwrap().sni_offset().unwrap();
+        let (result, sink) = run(&hello, params(vec![Method::Split(Part::with_sni_flag(2))]), Protocol::Tls, Some(sni)).await;
+        assert_eq!(sink.segments()[0].bytes, hello[..sni + 2]);
+        assert_eq!(result.methods_applied, [(Method::Split(Part::with_sni_flag(2)), sni + 2)]);
+    }
+
+    #[tokio::test]
+    async fn extension_splits_move_past_the_tlsrec_header() {
+        let hello = synthetic_client_hello("example.com", 512);
+        let offsets = tls_extension_offsets(&hello);
+        let mut params = params(Vec::new());
+        params.split_tls_extensions = true;
+        params.tlsrec = Some(TlsrecStrategy::Fixed(Part::absolute(10)));
+        let (result, sink) = run(&hello, params, Protocol::Tls, None).await;
+        assert!(result.tlsrec_applied);
+        let positions: Vec<usize> = result.methods_applied.iter().map(|&(_, pos)| pos).collect();
+        assert_eq!(positions, offsets.iter().map(|pos| pos + 5).collect::<Vec<_>>());
+        assert_eq!(sink.segments()[0].bytes.len(), offsets[0] + 5);
+    }
+
+    #[tokio::test]
+    async fn malformed_tls_is_passed_through_and_a_hello_without_sni_is_desynced() {
+        let hello = synthetic_client_hello("example.com", 512);
+        let sni = parse_client_hello(&hello).unwrap().sni_offset().unwrap();
+        let params = params(vec![Method::Split(Part::absolute(10))]);
+
+        let mut server_hello = hello.clone();
+        server_hello[5] = 0x02;
+        let mut sink = MockTcpStream::new();
+        let result = desync_hello_phrase(&mut &server_hello[..], &mut sink, 443, params.clone()).await.unwrap();
+        assert_eq!(result.protocol, Protocol::Other);
+        assert_eq!(sink.segments().len(), 1);
+
+        let mut without_sni = hello.clone();
+        without_sni[sni - 9..sni - 7].copy_from_slice(&[0x00, 0xfe]);
+        let mut sink = MockTcpStream::new();
+        let result = desync_hello_phrase(&mut &without_sni[..], &mut sink, 443, params).await.unwrap();
+        assert_eq!(result.protocol, Protocol::Tls);
+        assert_eq!(sink.segments()[0].bytes, without_sni[..10]);
+    }
+
+    #[tokio::test]
+    async fn parallel_connections_keep_the_first_attempt_params() {
+        let mut params = params(vec![Method::Split(Part::absolute(5))]);
+        params.retry_params = Some(Box::new(params.with_retry_split()));
+        let flows = FlowTracker::new(Duration::from_secs(60));
+        let client = IpAddr::from([192, 0, 2, 7]);
+
+        // a browser opening two connections to the same host at once
+        let first = flows.open(client, "example.com:443".to_owned());
+        let second = flows.open(client, "example.com:443".to_owned());
+        for flow in [&first, &second] {
+            assert_eq!(params.clone().escalate(flow.retries).methods, [Method::Split(Part::absolute(5))]);
+        }
+
+        drop((first, second));
+        let retry = flows.open(client, "example.com:443".to_owned());
+        assert_eq!(params.escalate(retry.retries).methods, [Method::Split(Part::absolute(1)), Method::Split(Part::absolute(5))]);
+    }
+
+    #[tokio::test]
+    async fn random_tlsrec_splits_inside_the_range() {
+        let hello = synthetic_client_hello("example.com", 512);
+        let mut params = params(Vec::new());
+        params.tlsrec = Some(TlsrecStrategy::Random { min: 4, max: 9 });
+        for _ in 0..20 {
+            let (result, sink) = run(&hello, params.clone(), Protocol::Tls, None).await;
+            assert!(result.tlsrec_applied);
+            let first_len = u16::from_be_bytes([sink.segments()[0].bytes[3], sink.segments()[0].bytes[4]]) as usize;
+            assert!((4..9).contains(&first_len), "{first_len}");
+        }
+    }
+
+    #[tokio::test]
+    async fn random_tlsrec_past_the_hello_is_skipped() {
+        let hello = synthetic_client_hello("example.com", 512);
+        let mut params = params(Vec::new());
+        params.tlsrec = Some(TlsrecStrategy::Random { min: hello.len(), max: hello.len() + 10 });
+        let (result, sink) = run(&hello, params, Protocol::Tls, None).await;
+        // not clamped to the last byte
+        assert!(!result.tlsrec_applied);
+        assert_eq!(sink.segments()[0].bytes, hello);
+    }
+
+    #[tokio::test]
+    async fn ipv6_split_only_applies_to_ipv6_peers() {
+        let mut params = params(Vec::new());
+        params.split_ipv6 = true;
+        let bytes = [b'x'; 64];
+        let (_, sink) = run(&bytes, params.clone(), Protocol::Other, None).await;
+        assert_eq!(sink.segments().len(), 1);
+
+        let peer = SocketAddr::from((std::net::Ipv6Addr::LOCALHOST, 443));
+        let (_, sink) = run_with(MockTcpStream::with_peer_addr(peer), &bytes, params, Protocol::Other, None).await;
+        assert_eq!(sink.segments()[0].bytes.len(), IPV6_HEADER_LEN);
+    }
+
+    #[tokio::test]
+    async fn segment_count_limit_stops_applying_methods() {
+        let mut params = params(vec![Method::Split(Part::absolute(2)), Method::Disorder(Part::absolute(5))]);
+        params.segment_count_limit = 1;
+        let (result, sink) = run(b"hello world", params, Protocol::Other, None).await;
+        assert_eq!(segments(&sink), expected(&[("he", 64, false), ("llo world", 64, false)]));
+        assert_eq!(result.methods_applied.len(), 1);
+    }
+
+    #[tokio::test]
+    async fn host_flag_follows_a_removed_header() {
+        let host = REQUEST.windows(11).position(|w| w == b"example.com").unwrap();
+        let mut params = params(vec![Method::Split(Part::with_host_flag(1))]);
+        params.http_remove_headers = vec!["user-agent".to_owned()];
+        let (_, sink) = run(REQUEST, params, Protocol::Http, Some(host)).await;
+        assert!(sink.segments()[0].bytes.ends_with(b"Host: e"));
+    }
+
+    #[test]
+    fn part_serde_round_trip_with_both_flags() {
+        let mut params = params(vec![
+            Method::Split(Part::with_sni_flag(1)),
+            Method::Disorder(Part::with_host_flag(2)),
+            Method::Oob(Part::absolute(3), 1),
+        ]);
+        params.tlsrec = Some(TlsrecStrategy::Fixed(Part::with_sni_flag(0).with_negative_offset(-2)));
+
+        let json = serde_json::to_value(&params).unwrap();
+        assert_eq!(json["methods"][0], serde_json::json!({ "split": { "pos": 1, "flag": "sni" } }));
+        assert_eq!(json["methods"][1], serde_json::json!({ "disorder": { "pos": 2, "flag": "host" } }));
+        // no flag and no offset leave their fields out
+        assert_eq!(json["methods"][2], serde_json::json!({ "oob": { "pos": 3 } }));
+
+        let round_tripped: Params = serde_json::from_value(json).unwrap();
+        assert_eq!(round_tripped.methods, params.methods);
+        assert!(matches!(round_tripped.tlsrec, Some(TlsrecStrategy::Fixed(part)) if part == Part::with_sni_flag(0).with_negative_offset(-2)));
+    }
+
+    #[test]
+    fn part_rejects_an_unknown_flag() {
+        let err = serde_json::from_str::<Part>(r#"{ "pos": 1, "flag": "tls" }"#).unwrap_err();
+        assert!(err.to_string().contains("unknown flag `tls`"), "{err}");
+    }
+
+    #[test]
+    fn oob_size_parses_last_and_round_trips() {
+        for (spec, method) in [
+            ("oob:3", Method::Oob(Part::absolute(3), 1)),
+            ("oob:3:size=2", Method::Oob(Part::absolute(3), 2)),
+            ("oob:-1:sni:size=4", Method::Oob(Part::with_sni_flag(0).with_negative_offset(-1), 4)),
+        ] {
+            assert_eq!(Method::try_from(spec).unwrap(), method);
+            assert_eq!(method.to_string(), spec);
+        }
+        // a size of 1 is the default and isn't written out
+        assert_eq!(Method::try_from("oob:3:size=1").unwrap().to_string(), "oob:3");
+    }
+
+    #[test]
+    fn oob_size_errors() {
+        let err = |spec: &str| Method::try_from(spec).unwrap_err().to_string();
+        assert_eq!(err("oob:3:size=0"), "invalid oob size `size=0`");
+        assert_eq!(err("oob:3:sni:size=x"), "invalid oob size `size=x`");
+        assert_eq!(err("oob:3:sni:2"), "invalid oob size `2`");
+        assert_eq!(err("split:3:size=2"), "unexpected trailing input in `split:3:size=2`");
+        assert_eq!(err("oob:3:size=2:sni"), "unexpected trailing input in `oob:3:size=2:sni`");
+    }
+
+    #[test]
+    fn oob_size_in_config_files() {
+        let method: Method = serde_json::from_value(serde_json::json!({ "oob": { "pos": 3, "flag": "sni", "size": 2 } })).unwrap();
+        assert_eq!(method, Method::Oob(Part::with_sni_flag(3), 2));
+        assert_eq!(serde_json::to_value(&method).unwrap(), serde_json::json!({ "oob": { "pos": 3, "flag": "sni", "size": 2 } }));
+        assert!(serde_json::from_value::<Method>(serde_json::json!({ "oob": { "pos": 3, "size": 0 } })).is_err());
+    }
+
+    #[tokio::test]
+    async fn oob_size_sends_more_bytes_in_the_urgent_segment() {
+        if !platform::check_oob_support() {
+            return;
+        }
+        let (_, sink) = run(b"hello world", params(vec![Method::Oob(Part::absolute(3), 2)]), Protocol::Other, None).await;
+        assert_eq!(segments(&sink), expected(&[("hella", 64, true), ("o world", 64, false)]));
+        assert_eq!(sink.received(), b"hello world");
+    }
+
+    #[tokio::test]
+    async fn oob_past_the_end_is_skipped() {
+        if !platform::check_oob_support() {
+            return;
+        }
+        let (result, sink) = run(b"hello world", params(vec![Method::Oob(Part::absolute(3), 20)]), Protocol::Other, None).await;
+        assert!(result.methods_applied.is_empty());
+        assert_eq!(segments(&sink), expected(&[("hello world", 64, false)]));
+    }
+
+    #[test]
+    fn split_composite_takes_method_specs() {
+        assert_eq!(parse_split_composite("disorder:1, split:40,oob:-1:sni:size=2").unwrap(), [
+            Method::Disorder(Part::absolute(1)),
+            Method::Split(Part::absolute(40)),
+            Method::Oob(Part::with_sni_flag(0).with_negative_offset(-1), 2),
+        ]);
+        assert_eq!(parse_split_composite("split:0:header=Host").unwrap(), [Method::Split(Part::with_header_flag(0, "Host".to_owned()))]);
+    }
+
+    #[test]
+    fn split_composite_errors() {
+        let err = |s: &str| parse_split_composite(s).unwrap_err();
+        // the position first is the order --method doesn't take
+        assert_eq!(err("1:disorder"), "invalid position `disorder`");
+        assert_eq!(err("split:1,disorder"), "missing position in `disorder`");
+        assert_eq!(err("split:1,"), "missing position in ``");
+        assert_eq!(err("split:x"), "invalid position `x`");
+        assert_eq!(err("split:1:tls"), "unknown flag `tls`");
+    }
+
+    #[test]
+    fn normalize_orders_methods_at_the_same_position_by_kind() {
+        let split = Method::Split(Part::absolute(10));
+        let disorder = Method::Disorder(Part::absolute(10));
+        for methods in [vec![split.clone(), disorder.clone()], vec![disorder.clone(), split.clone()]] {
+            let mut params = params(methods);
+            params.normalize();
+            assert_eq!(params.methods, [split.clone(), disorder.clone()]);
+        }
+    }
+
+    #[test]
+    fn normalize_drops_a_method_given_twice() {
+        let mut params = params(vec![Method::Oob(Part::absolute(20), 1), Method::Split(Part::absolute(10)), Method::Oob(Part::absolute(20), 1)]);
+        params.normalize();
+        assert_eq!(params.methods, [Method::Split(Part::absolute(10)), Method::Oob(Part::absolute(20), 1)]);
+    }
+
+    fn hash_of<T: std::hash::Hash>(value: &T) -> u64 {
+        use std::hash::{BuildHasher, RandomState};
+        thread_local!(static STATE: RandomState = RandomState::new());
+        STATE.with(|state| state.hash_one(value))
+    }
+
+    #[test]
+    fn equal_methods_hash_equal() {
+        let a = Method::Split(Part::with_header_flag(2, "Host".to_owned()).with_negative_offset(-1));
+        let b = Method::Split(Part::with_header_flag(2, "Host".to_owned()).with_negative_offset(-1));
+        assert_eq!(a, b);
+        assert_eq!(hash_of(&a), hash_of(&b));
+        assert_eq!(hash_of(a.part()), hash_of(b.part()));
+        assert_eq!(hash_of(&Flag::OffsetSni), hash_of(&Flag::OffsetSni));
+    }
+
+    #[test]
+    fn methods_differing_in_kind_flag_or_offset_are_distinct() {
+        let methods = [
+            Method::Split(Part::absolute(2)),
+            Method::Disorder(Part::absolute(2)),
+            Method::Split(Part::with_sni_flag(2)),
+            Method::Split(Part::with_host_flag(2)),
+            Method::Split(Part::with_header_flag(2, "Host".to_owned())),
+            Method::Split(Part::with_header_flag(2, "Accept".to_owned())),
+            Method::Split(Part::with_sni_flag(2).with_negative_offset(-1)),
+        ];
+        let set: HashSet<Method> = methods.iter().chain(&methods).cloned().collect();
+        assert_eq!(set.len(), methods.len());
+    }
+
+    #[tokio::test]
+    async fn sni_end_flag_counts_from_the_end_of_the_name() {
+        let hello = synthetic_client_hello("example.com", 512);
+        let end = sni_end_offset(&hello).unwrap();
+        let method = Method::try_from("split:-1:sni-end").unwrap();
+        assert_eq!(method.to_string(), "split:-1:sni-end");
+        let (_, sink) = run(&hello, params(vec![method]), Protocol::Tls, None).await;
+        assert_eq!(sink.segments()[0].bytes, hello[..end - 1]);
+    }
+
+    #[tokio::test]
+    async fn sni_end_flag_follows_a_tlsrec_split() {
+        let hello = synthetic_client_hello("example.com", 512);
+        let end = sni_end_offset(&hello).unwrap();
+        let mut params = params(vec![Method::Split(Part::with_sni_end_flag(0))]);
+        params.tlsrec = Some(TlsrecStrategy::Fixed(Part::absolute(10)));
+        let (_, sink) = run(&hello, params, Protocol::Tls, None).await;
+        // the second record header sits before the name
+        assert!(sink.segments()[0].bytes.ends_with(b"example.com"));
+        assert_eq!(sink.segments()[0].bytes.len(), end + 5);
+    }
+}
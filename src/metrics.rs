@@ -0,0 +1,130 @@
+use serde::{Deserialize, Serialize};
+use std::{
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::Duration,
+};
+use tokio::time::Instant;
+use tracing::{debug, info};
+
+// target of the connection open and close events
+pub const TARGET: &str = "rust_dpi::connection";
+
+// set by --log-connections-only. connection events are logged at info
+// instead of debug, and the per method events are left out
+static CONNECTIONS_ONLY: AtomicBool = AtomicBool::new(false);
+
+pub fn set_connections_only(enabled: bool) {
+    CONNECTIONS_ONLY.store(enabled, Ordering::Relaxed);
+}
+
+pub fn connections_only() -> bool {
+    CONNECTIONS_ONLY.load(Ordering::Relaxed)
+}
+
+// a tracing level has to be known at the call site
+macro_rules! connection_event {
+    ($($arg:tt)+) => {
+        if connections_only() {
+            info!(target: TARGET, $($arg)+)
+        } else {
+            debug!(target: TARGET, $($arg)+)
+        }
+    };
+}
+
+// totals over all connections since start
+pub static CONNECTIONS: AtomicU64 = AtomicU64::new(0);
+pub static ERRORS: AtomicU64 = AtomicU64::new(0);
+pub static BYTES_IN: AtomicU64 = AtomicU64::new(0);
+pub static BYTES_OUT: AtomicU64 = AtomicU64::new(0);
+// connections currently being handled
+pub static ACTIVE: AtomicU64 = AtomicU64::new(0);
+
+// point in time copy of the totals, served on --status-socket
+#[derive(Debug, Serialize, Deserialize)]
+pub struct Stats {
+    pub uptime_secs: u64,
+    pub active: u64,
+    pub connections: u64,
+    pub errors: u64,
+    pub bytes_in: u64,
+    pub bytes_out: u64,
+}
+
+impl Stats {
+    pub fn snapshot(uptime: Duration) -> Self {
+        Stats {
+            uptime_secs: uptime.as_secs(),
+            active: ACTIVE.load(Ordering::Relaxed),
+            connections: CONNECTIONS.load(Ordering::Relaxed),
+            errors: ERRORS.load(Ordering::Relaxed),
+            bytes_in: BYTES_IN.load(Ordering::Relaxed),
+            bytes_out: BYTES_OUT.load(Ordering::Relaxed),
+        }
+    }
+}
+
+static CONNECTION_COUNTER: AtomicU64 = AtomicU64::new(0);
+
+// recorded on the connection span, so every event of one connection can be
+// told apart from the interleaved others
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Serialize)]
+pub struct ConnectionId(u64);
+
+impl ConnectionId {
+    pub fn next() -> Self {
+        ConnectionId(CONNECTION_COUNTER.fetch_add(1, Ordering::Relaxed) + 1)
+    }
+}
+
+impl std::fmt::Display for ConnectionId {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+// filled in while a connection is handled and reported when dropped, so
+// every exit path (including a cancelled or panicking task) is counted
+#[derive(Debug)]
+pub struct ConnectionMetrics {
+    pub id: ConnectionId,
+    start: Instant,
+    // client to upstream
+    pub bytes_out: u64,
+    // upstream to client
+    pub bytes_in: u64,
+    pub desync_count: usize,
+    pub error: bool,
+}
+
+impl ConnectionMetrics {
+    pub fn new(id: ConnectionId) -> Self {
+        ACTIVE.fetch_add(1, Ordering::Relaxed);
+        connection_event!("connection opened");
+        ConnectionMetrics { id, start: Instant::now(), bytes_out: 0, bytes_in: 0, desync_count: 0, error: false }
+    }
+
+    pub fn duration(&self) -> Duration {
+        self.start.elapsed()
+    }
+}
+
+impl Drop for ConnectionMetrics {
+    fn drop(&mut self) {
+        ACTIVE.fetch_sub(1, Ordering::Relaxed);
+        let connections = CONNECTIONS.fetch_add(1, Ordering::Relaxed) + 1;
+        let errors = ERRORS.fetch_add(self.error as u64, Ordering::Relaxed) + self.error as u64;
+        BYTES_IN.fetch_add(self.bytes_in, Ordering::Relaxed);
+        BYTES_OUT.fetch_add(self.bytes_out, Ordering::Relaxed);
+        connection_event!(
+            duration_ms = self.duration().as_millis() as u64,
+            bytes_in = self.bytes_in,
+            bytes_out = self.bytes_out,
+            desync_count = self.desync_count,
+            error = self.error,
+            connections,
+            errors,
+            "connection closed"
+        );
+    }
+}
@@ -0,0 +1,128 @@
+use crate::sink::{DesyncSink, TtlControl};
+use std::{
+    cell::{Cell, RefCell},
+    io::{Error, ErrorKind, Result},
+    net::SocketAddr,
+    pin::Pin,
+    task::{Context, Poll},
+};
+use tokio::io::AsyncWrite;
+
+const DEFAULT_TTL: u32 = 64;
+
+// an upstream socket for tests. writes between flushes make one segment,
+// a ttl change or urgent data always starts a new one
+#[derive(Debug)]
+pub struct MockTcpStream {
+    segments: RefCell<Vec<Segment>>,
+    ttl: Cell<u32>,
+    // every value passed to set_ttl, in order
+    ttl_changes: RefCell<Vec<u32>>,
+    open: Cell<bool>,
+    peer_addr: SocketAddr,
+    // set_ttl(1) fails like it does for an unprivileged socket under some
+    // sandboxes
+    refuse_low_ttl: bool,
+}
+
+#[derive(Debug, PartialEq)]
+pub struct Segment {
+    pub bytes: Vec<u8>,
+    pub ttl: u32,
+    pub oob: bool,
+}
+
+impl MockTcpStream {
+    pub fn new() -> Self {
+        MockTcpStream::with_peer_addr(SocketAddr::from(([192, 0, 2, 1], 443)))
+    }
+
+    pub fn with_peer_addr(peer_addr: SocketAddr) -> Self {
+        MockTcpStream {
+            segments: RefCell::default(),
+            ttl: Cell::new(DEFAULT_TTL),
+            ttl_changes: RefCell::default(),
+            open: Cell::new(false),
+            peer_addr,
+            refuse_low_ttl: false,
+        }
+    }
+
+    pub fn refusing_low_ttl() -> Self {
+        MockTcpStream { refuse_low_ttl: true, ..MockTcpStream::new() }
+    }
+
+    pub fn segments(&self) -> std::cell::Ref<'_, Vec<Segment>> {
+        self.segments.borrow()
+    }
+
+    pub fn ttl_changes(&self) -> Vec<u32> {
+        self.ttl_changes.borrow().clone()
+    }
+
+    // everything the server would read, urgent bytes taken out like a
+    // receiver without SO_OOBINLINE does
+    pub fn received(&self) -> Vec<u8> {
+        let mut received = Vec::new();
+        for segment in self.segments().iter() {
+            match segment.oob {
+                true => received.extend_from_slice(&segment.bytes[..segment.bytes.len() - 1]),
+                false => received.extend_from_slice(&segment.bytes),
+            }
+        }
+        received
+    }
+}
+
+impl TtlControl for MockTcpStream {
+    fn ttl(&self) -> Result<u32> {
+        Ok(self.ttl.get())
+    }
+
+    fn set_ttl(&self, ttl: u32) -> Result<()> {
+        if self.refuse_low_ttl && ttl == 1 {
+            return Err(Error::from(ErrorKind::PermissionDenied));
+        }
+        self.ttl.set(ttl);
+        self.ttl_changes.borrow_mut().push(ttl);
+        self.open.set(false);
+        Ok(())
+    }
+}
+
+impl DesyncSink for MockTcpStream {
+    fn send_oob(&self, data: &[u8]) -> Result<usize> {
+        self.segments.borrow_mut().push(Segment { bytes: data.to_vec(), ttl: self.ttl.get(), oob: true });
+        self.open.set(false);
+        Ok(data.len())
+    }
+
+    fn set_cork(&self, _cork: bool) -> Result<()> {
+        Ok(())
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        Ok(self.peer_addr)
+    }
+}
+
+impl AsyncWrite for MockTcpStream {
+    fn poll_write(self: Pin<&mut Self>, _cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let mut segments = self.segments.borrow_mut();
+        match segments.last_mut() {
+            Some(last) if self.open.get() => last.bytes.extend_from_slice(buf),
+            _ => segments.push(Segment { bytes: buf.to_vec(), ttl: self.ttl.get(), oob: false }),
+        }
+        self.open.set(true);
+        Poll::Ready(Ok(buf.len()))
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        self.open.set(false);
+        Poll::Ready(Ok(()))
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Poll::Ready(Ok(()))
+    }
+}
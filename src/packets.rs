@@ -1,63 +1,400 @@
-use core::str;
-use memchr::memmem;
-
-pub fn is_tls_hello(buffer: &[u8]) -> Option<usize> {
-    if buffer.len() > 5
-        && buffer.starts_with(&[0x16, 0x03])
-        && buffer[5] == 0x01 {
-        let server_name_extension = memmem::find(buffer, &[0, 0]);
-        return server_name_extension.map(|idx| idx + 9);
-    }
-    None
-}
-
-pub fn is_http(buffer: &[u8]) -> Option<usize> {
-    const METHODS: [&str; 9] = [
-        "HEAD", "GET", "POST", "PUT", "DELETE",
-        "OPTIONS", "CONNECT", "TRACE", "PATCH"
-    ];
-    for method in METHODS {
-        if buffer.starts_with(method.as_bytes()) {
-            let str = String::from_utf8_lossy(buffer);
-            if let Some(idx) = str.to_lowercase()
-                .find("\nhost:")
-                .map(|idx| idx + 6) {
-                let mut offset = 0;
-                for ch in str[idx..].chars() {
-                    if ch != ' ' {
-                        return Some(idx + offset);
-                    }
-                    offset += 1;
-                }
+use core::{ops::Range, str};
+use crate::{
+    http_parser::parse_http_request,
+    tls_parser::{parse_client_hello, parse_dtls_client_hello, TlsParseError, EXTENSION_SERVER_NAME}
+};
+
+// offset of the server name in a ClientHello. `NotTls` is anything else, the
+// other errors are tls that can't be desynced by sni
+pub fn is_tls_hello(buffer: &[u8]) -> Result<usize, TlsParseError> {
+    parse_client_hello(buffer)?.sni_offset().ok_or(TlsParseError::MissingExtension)
+}
+
+// offset of the server name in a dtls 1.0 or 1.2 ClientHello
+pub fn is_dtls_hello(buffer: &[u8]) -> Option<usize> {
+    parse_dtls_client_hello(buffer).ok()?.sni_offset()
+}
+
+// session_ticket extension carrying a ticket alongside a non-empty session id
+pub fn is_tls_session_ticket(buffer: &[u8]) -> bool {
+    let Ok(hello) = parse_client_hello(buffer) else {
+        return false;
+    };
+    !hello.session_id().is_empty()
+        && hello.extension(0x0023).is_some_and(|ext| !ext.data.is_empty())
+}
+
+// encrypted_client_hello, the real sni is inside the encrypted inner hello
+pub fn has_ech_extension(buffer: &[u8]) -> bool {
+    parse_client_hello(buffer).is_ok_and(|hello| hello.extension(0xfe0d).is_some())
+}
+
+// offset of the type field of every extension
+pub fn tls_extension_offsets(buffer: &[u8]) -> Vec<usize> {
+    parse_client_hello(buffer)
+        .map(|hello| hello.extensions().iter().map(|ext| ext.offset - 4).collect())
+        .unwrap_or_default()
+}
+
+// offset just past the cipher suite list, where the compression methods start
+pub fn cipher_suites_end_offset(buffer: &[u8]) -> Option<usize> {
+    let hello = parse_client_hello(buffer).ok()?;
+    // record and handshake headers, version, random, session id and cipher suites with their lengths
+    Some(5 + 4 + 2 + 32 + 1 + hello.session_id().len() + 2 + 2 * hello.cipher_suites().count())
+}
+
+// offset just past the last byte of the server name
+pub fn sni_end_offset(buffer: &[u8]) -> Option<usize> {
+    let hello = parse_client_hello(buffer).ok()?;
+    Some(hello.sni_offset()? + hello.sni()?.len())
+}
+
+// replaces the server name and fixes up every length that covers it. only
+// a complete hello in a single record is rewritten
+pub fn replace_sni(buffer: &mut Vec<u8>, sni: &str) -> bool {
+    try_replace_sni(buffer, sni.as_bytes()).is_some()
+}
+
+// pads the server name with zero bytes up to `target_len`, false when it is
+// already longer
+pub fn pad_sni_to_length(buffer: &mut Vec<u8>, target_len: usize) -> bool {
+    let Some(mut name) = parse_client_hello(buffer).ok().and_then(|hello| hello.sni()).map(|sni| sni.as_bytes().to_vec()) else {
+        return false;
+    };
+    if target_len < name.len() {
+        return false;
+    }
+    name.resize(target_len, 0);
+    try_replace_sni(buffer, &name).is_some()
+}
+
+fn try_replace_sni(buffer: &mut Vec<u8>, sni: &[u8]) -> Option<()> {
+    let hello = parse_client_hello(buffer).ok()?;
+    let name_start = hello.sni_offset()?;
+    let ext_start = hello.extension(EXTENSION_SERVER_NAME)?.offset;
+    let extensions_start = hello.extensions().first()?.offset - 4;
+    let read_u16 = |at: usize| u16::from_be_bytes([buffer[at], buffer[at + 1]]) as isize;
+    let record_len = read_u16(3) as usize;
+    let handshake_len = u32::from_be_bytes([0, buffer[6], buffer[7], buffer[8]]) as isize;
+    if buffer.len() != 5 + record_len || record_len != 4 + handshake_len as usize {
+        return None;
+    }
+
+    let old_len = read_u16(name_start - 2) as usize;
+    let delta = sni.len() as isize - old_len as isize;
+    // record, extensions, server_name extension, server_name_list and host_name lengths
+    let fields = [3, extensions_start - 2, ext_start - 2, ext_start, name_start - 2];
+    let mut values = [0u16; 5];
+    for (value, &at) in values.iter_mut().zip(&fields) {
+        *value = u16::try_from(read_u16(at) + delta).ok()?;
+    }
+    let handshake_len = u32::try_from(handshake_len + delta).ok()?;
+
+    for (value, at) in values.into_iter().zip(fields) {
+        buffer[at..at + 2].copy_from_slice(&value.to_be_bytes());
+    }
+    buffer[6..9].copy_from_slice(&handshake_len.to_be_bytes()[1..]);
+    buffer.splice(name_start..name_start + old_len, sni.iter().copied());
+    Some(())
+}
+
+pub fn is_partial_tls_record(buffer: &[u8]) -> bool {
+    if !buffer.starts_with(&[0x16, 0x03]) {
+        return false;
+    }
+    buffer.len() < 5 || buffer.len() < 5 + u16::from_be_bytes([buffer[3], buffer[4]]) as usize
+}
+
+pub fn find_http_header(buffer: &[u8], name: &str) -> Option<usize> {
+    parse_http_request(buffer)?.header(name).map(|header| header.value_offset)
+}
+
+// start of every header line, splitting there sends each header in its own
+// segment. the last header keeps the blank line that ends the head
+pub fn http_header_offsets(buffer: &[u8]) -> Vec<usize> {
+    let end = memchr::memmem::find(buffer, b"\r\n\r\n").unwrap_or(buffer.len());
+    memchr::memmem::find_iter(&buffer[..end], b"\r\n").map(|pos| pos + 2).collect()
+}
+
+// offset of the request target in the request line
+pub fn url_start_offset(buffer: &[u8]) -> Option<usize> {
+    parse_http_request(buffer).map(|request| request.path_offset)
+}
+
+pub fn rewrite_http_host(buffer: &mut Vec<u8>, from: &str, to: &str) -> bool {
+    if to.is_empty() || to.bytes().any(|b| b.is_ascii_whitespace() || b.is_ascii_control()) {
+        return false;
+    }
+    let Some(host) = parse_http_request(buffer).and_then(|request| request.header("host")) else {
+        return false;
+    };
+    let (start, value) = (host.value_offset, host.value);
+    // keep the port if present, e.g. `Host: example.com:8080`
+    let host_len = value.iter().position(|&b| b == b':').unwrap_or(value.len());
+    if !value[..host_len].eq_ignore_ascii_case(from.as_bytes()) {
+        return false;
+    }
+    buffer.splice(start..start + host_len, to.bytes());
+    true
+}
+
+// drops the whole `Name: Value\r\n` line of the first header with this name
+// and returns where the line was
+pub fn remove_http_header(buffer: &mut Vec<u8>, name: &str) -> Option<Range<usize>> {
+    let value_offset = parse_http_request(buffer)?.header(name)?.value_offset;
+    let start = memchr::memrchr(b'\n', &buffer[..value_offset]).map_or(0, |pos| pos + 1);
+    // the parser only yields header lines that end in a newline
+    let end = memchr::memchr(b'\n', &buffer[value_offset..]).map(|pos| value_offset + pos + 1)?;
+    buffer.drain(start..end);
+    Some(start..end)
+}
+
+// percent-encodes one character of the first `keyword` in the request target,
+// e.g. `/blocked` becomes `/%62locked`. an unreserved ascii character outside
+// of existing `%XX` escapes is preferred, otherwise every byte of the first
+// non-ascii character is encoded
+pub fn uri_encode_keyword(buffer: &mut Vec<u8>, keyword: &str) -> bool {
+    let Some((pos, len)) = parse_http_request(buffer).and_then(|request| {
+        let path = request.path;
+        let found = memchr::memmem::find(path, keyword.as_bytes()).filter(|_| !keyword.is_empty())?;
+        let mut escaped = vec![false; path.len()];
+        for i in memchr::memchr_iter(b'%', path) {
+            if path.get(i + 1..i + 3).is_some_and(|hex| hex.iter().all(u8::is_ascii_hexdigit)) {
+                escaped[i..i + 3].fill(true);
             }
-            return None;
         }
-    }
-    None
+        let unreserved = keyword.char_indices()
+            .find(|&(i, c)| (c.is_ascii_alphanumeric() || "-._~".contains(c)) && !escaped[found + i])
+            .map(|(i, _)| (i, 1));
+        let multibyte = keyword.char_indices()
+            .find(|(_, c)| !c.is_ascii())
+            .map(|(i, c)| (i, c.len_utf8()));
+        unreserved.or(multibyte).map(|(i, len)| (request.path_offset + found + i, len))
+    }) else {
+        return false;
+    };
+    let encoded: Vec<u8> = buffer[pos..pos + len].iter()
+        .flat_map(|b| format!("%{b:02X}").into_bytes())
+        .collect();
+    buffer.splice(pos..pos + len, encoded);
+    true
 }
 
-pub fn part_tls(buffer: &mut Vec<u8>, pos: usize) {
-    let r_sz = ((buffer[3] as u16) << 8) | buffer[4] as u16;
-    let mut vec1 = Vec::new();
-    buffer[..3].clone_into(&mut vec1);
+pub fn downgrade_http_version(buffer: &mut [u8]) -> bool {
+    const FROM: &[u8] = b"HTTP/1.1\r\n";
+    let line_end = match memchr::memchr(b'\n', buffer) {
+        Some(pos) => pos + 1,
+        None => return false
+    };
+    if !buffer[..line_end].ends_with(FROM) {
+        return false;
+    }
+    buffer[line_end - FROM.len()..line_end].copy_from_slice(b"HTTP/1.0\r\n");
+    true
+}
 
-    let mut v = buffer.split_off(5 + pos);
-    buffer.extend_from_slice(&vec1);
-    buffer.append(&mut v);
+// a response head with `Transfer-Encoding: chunked`
+pub fn is_chunked_http_response(buffer: &[u8]) -> bool {
+    if !buffer.starts_with(b"HTTP/1.") {
+        return false;
+    }
+    let end = memchr::memmem::find(buffer, b"\r\n\r\n").unwrap_or(buffer.len());
+    buffer[..end].split(|&b| b == b'\n').skip(1).any(|line| {
+        let Some(colon) = memchr::memchr(b':', line) else {
+            return false;
+        };
+        line[..colon].trim_ascii().eq_ignore_ascii_case(b"transfer-encoding")
+            && line[colon + 1..].trim_ascii().to_ascii_lowercase().ends_with(b"chunked")
+    })
+}
 
-    let mut v = buffer.split_off(8 + pos);
-    buffer.extend_from_slice(&convert_u16_to_two_u8s_be(htons(r_sz - pos as u16)));
-    buffer.append(&mut v);
+// adds `name: value` to the empty trailer section after the last chunk. the
+// terminator has to be in the buffer, at its start or after a chunk's data
+pub fn inject_http_trailer(buffer: &mut Vec<u8>, name: &str, value: &str) -> bool {
+    const LAST_CHUNK: &[u8] = b"0\r\n\r\n";
+    let at = match memchr::memmem::rfind(buffer, b"\r\n0\r\n\r\n") {
+        Some(pos) => pos + 2,
+        None if buffer.starts_with(LAST_CHUNK) => 0,
+        None => return false
+    };
+    let trailer = format!("{name}: {value}\r\n");
+    // before the blank line that ends the message
+    let at = at + LAST_CHUNK.len() - 2;
+    buffer.splice(at..at, trailer.bytes());
+    true
+}
 
-    let vec2 = convert_u16_to_two_u8s_be(htons( pos as u16));
-    buffer[3] = vec2[0];
-    buffer[4] = vec2[1];
+pub fn part_tls(buffer: &mut Vec<u8>, pos: usize) -> Result<(), TlsParseError> {
+    part_tls_multi(buffer, &[pos])
 }
 
-fn htons(val: u16) -> u16 {
-    return ((val & 0x00FF) << 8) | ((val & 0xFF00) >> 8)
+// cuts the first record at every position, counted from the start of its
+// payload, into positions.len() + 1 records. every record keeps at least
+// one byte of payload, anything else is rejected before the buffer is touched
+pub fn part_tls_multi(buffer: &mut Vec<u8>, positions: &[usize]) -> Result<(), TlsParseError> {
+    if buffer.len() < 5 {
+        return Err(TlsParseError::TruncatedRecord);
+    }
+    let r_sz = u16::from_be_bytes([buffer[3], buffer[4]]) as usize;
+    let mut bounds = Vec::with_capacity(positions.len() + 2);
+    bounds.push(0);
+    bounds.extend_from_slice(positions);
+    bounds.sort_unstable();
+    bounds.dedup();
+    if bounds.len() != positions.len() + 1 || bounds.last().is_some_and(|&pos| pos >= r_sz || 5 + pos > buffer.len()) {
+        return Err(TlsParseError::InvalidSplitPosition);
+    }
+    bounds.push(r_sz);
+
+    let mut records = Vec::with_capacity(buffer.len() + 5 * positions.len());
+    for window in bounds.windows(2) {
+        let (start, end) = (window[0], window[1]);
+        records.extend_from_slice(&buffer[..3]);
+        records.extend_from_slice(&((end - start) as u16).to_be_bytes());
+        // the last record may still be incomplete in the buffer
+        records.extend_from_slice(&buffer[5 + start..buffer.len().min(5 + end)]);
+    }
+    if buffer.len() > 5 + r_sz {
+        records.extend_from_slice(&buffer[5 + r_sz..]);
+    }
+    *buffer = records;
+    Ok(())
 }
 
-fn convert_u16_to_two_u8s_be(integer: u16) -> [u8; 2] {
-    [integer as u8, (integer >> 8) as u8]
-}
\ No newline at end of file
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::benchmark::synthetic_client_hello;
+
+    // (content type and version, payload) of every record, None when a
+    // record runs past the end of the buffer
+    fn records(mut buffer: &[u8]) -> Option<Vec<([u8; 3], &[u8])>> {
+        let mut records = Vec::new();
+        while !buffer.is_empty() {
+            let len = u16::from_be_bytes([*buffer.get(3)?, *buffer.get(4)?]) as usize;
+            records.push((buffer[..3].try_into().ok()?, buffer.get(5..5 + len)?));
+            buffer = &buffer[5 + len..];
+        }
+        Some(records)
+    }
+
+    #[test]
+    fn is_tls_hello_tells_malformed_tls_from_other_protocols() {
+        let hello = synthetic_client_hello("example.com", 200);
+        let sni = is_tls_hello(&hello).unwrap();
+        assert_eq!(&hello[sni..sni + 11], b"example.com");
+
+        assert_eq!(is_tls_hello(b"GET / HTTP/1.1\r\n\r\n"), Err(TlsParseError::NotTls));
+        // the server_name extension renamed to an unknown one
+        let mut without_sni = hello.clone();
+        without_sni[sni - 9..sni - 7].copy_from_slice(&[0x00, 0xfe]);
+        assert_eq!(is_tls_hello(&without_sni), Err(TlsParseError::MissingExtension));
+        // a ServerHello in the handshake record
+        let mut server_hello = hello.clone();
+        server_hello[5] = 0x02;
+        assert_eq!(is_tls_hello(&server_hello), Err(TlsParseError::InvalidHandshake));
+    }
+
+    #[test]
+    fn part_tls_multi_cuts_at_every_position() {
+        let hello = synthetic_client_hello("example.com", 200);
+        let mut buffer = hello.clone();
+        part_tls_multi(&mut buffer, &[10, 40]).unwrap();
+
+        let records = records(&buffer).unwrap();
+        assert_eq!(records.len(), 3);
+        assert!(records.iter().all(|(header, _)| *header == hello[..3]));
+        assert_eq!(records.iter().map(|(_, payload)| payload.len()).collect::<Vec<_>>(), [10, 30, hello.len() - 5 - 40]);
+        let payloads: Vec<u8> = records.iter().flat_map(|(_, payload)| payload.iter().copied()).collect();
+        assert_eq!(payloads, hello[5..]);
+    }
+
+    #[test]
+    fn remove_http_header_returns_the_removed_line() {
+        let mut buffer = b"GET / HTTP/1.1\r\nUser-Agent: x\r\nHost: example.com\r\n\r\n".to_vec();
+        assert_eq!(remove_http_header(&mut buffer, "user-agent"), Some(16..31));
+        assert_eq!(buffer, b"GET / HTTP/1.1\r\nHost: example.com\r\n\r\n");
+        assert_eq!(remove_http_header(&mut buffer, "accept"), None);
+    }
+
+    #[test]
+    fn part_tls_rejects_positions_outside_the_payload() {
+        let hello = synthetic_client_hello("example.com", 200);
+        let payload_len = hello.len() - 5;
+        for pos in [0, payload_len, payload_len + 1, usize::MAX] {
+            let mut buffer = hello.clone();
+            assert_eq!(part_tls(&mut buffer, pos), Err(TlsParseError::InvalidSplitPosition), "pos {pos}");
+            assert_eq!(buffer, hello);
+        }
+        let mut buffer = hello[..4].to_vec();
+        assert_eq!(part_tls(&mut buffer, 1), Err(TlsParseError::TruncatedRecord));
+    }
+
+    #[test]
+    fn part_tls_at_the_last_payload_byte() {
+        let hello = synthetic_client_hello("example.com", 200);
+        let mut buffer = hello.clone();
+        part_tls(&mut buffer, hello.len() - 6).unwrap();
+        let records = records(&buffer).unwrap();
+        assert_eq!(records.len(), 2);
+        assert_eq!(records[1].1, [hello[hello.len() - 1]]);
+    }
+
+    #[test]
+    fn part_tls_writes_lengths_big_endian() {
+        let hello = synthetic_client_hello("example.com", 1000);
+        let second_len = hello.len() - 5 - 300;
+        let mut buffer = hello.clone();
+        part_tls(&mut buffer, 300).unwrap();
+        // 300 is 0x012c, a little endian length would read as 0x2c01
+        assert_eq!(buffer[3..5], [0x01, 0x2c]);
+        assert_eq!(buffer[5 + 300 + 3..5 + 300 + 5], (second_len as u16).to_be_bytes());
+    }
+
+    #[test]
+    fn http_header_offsets_of_a_five_header_request() {
+        let request = b"GET / HTTP/1.1\r\nHost: example.com\r\nUser-Agent: t\r\nAccept: */*\r\nConnection: close\r\nX-A: 1\r\n\r\nbody";
+        let offsets = http_header_offsets(request);
+        assert_eq!(offsets.len(), 5);
+        // each header starts a segment, the last one runs to the end
+        let starts: Vec<&[u8]> = offsets.iter().map(|&pos| &request[pos..pos + 4]).collect();
+        assert_eq!(starts, [b"Host", b"User", b"Acce", b"Conn", b"X-A:"]);
+    }
+
+    #[test]
+    fn http_header_offsets_stop_at_the_body() {
+        let request = b"POST / HTTP/1.1\r\nHost: a\r\n\r\nline\r\nline\r\n";
+        assert_eq!(http_header_offsets(request), [17]);
+    }
+
+    #[test]
+    fn inject_http_trailer_after_several_chunks() {
+        let mut buffer = b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n".to_vec();
+        assert!(is_chunked_http_response(&buffer));
+        assert!(inject_http_trailer(&mut buffer, "X-Checksum", "abc"));
+        assert_eq!(buffer, b"HTTP/1.1 200 OK\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWiki\r\n5\r\npedia\r\n0\r\nX-Checksum: abc\r\n\r\n");
+    }
+
+    #[test]
+    fn inject_http_trailer_in_a_buffer_holding_only_the_last_chunk() {
+        let mut buffer = b"0\r\n\r\n".to_vec();
+        assert!(inject_http_trailer(&mut buffer, "X-A", "1"));
+        assert_eq!(buffer, b"0\r\nX-A: 1\r\n\r\n");
+    }
+
+    #[test]
+    fn inject_http_trailer_needs_the_last_chunk() {
+        // a chunk that ends in 0 isn't the last chunk
+        let mut buffer = b"a\r\n0123456780\r\n".to_vec();
+        assert!(!inject_http_trailer(&mut buffer, "X-A", "1"));
+        assert_eq!(buffer, b"a\r\n0123456780\r\n");
+    }
+
+    #[test]
+    fn sni_end_offset_is_just_past_the_name() {
+        let hello = synthetic_client_hello("example.com", 300);
+        let end = sni_end_offset(&hello).unwrap();
+        assert_eq!(&hello[end - 11..end], b"example.com");
+        assert_eq!(sni_end_offset(b"GET / HTTP/1.1\r\n\r\n"), None);
+    }
+}
@@ -0,0 +1,145 @@
+use socket2::{SockRef, TcpKeepalive};
+use std::{
+    io::Result,
+    net::{TcpListener, TcpStream},
+    sync::OnceLock,
+    time::Duration,
+};
+use tracing::warn;
+
+// socket options that only some platforms have. every platform specific
+// socket call goes through here
+
+// SO_BINDTODEVICE, binding to an interface by name
+#[allow(dead_code)]
+pub const SUPPORTS_BINDTODEVICE: bool = cfg!(any(target_os = "linux", target_os = "android", target_os = "fuchsia"));
+// IP_TRANSPARENT, connecting from a foreign source address
+#[allow(dead_code)]
+pub const SUPPORTS_TRANSPARENT: bool = cfg!(any(target_os = "linux", target_os = "android"));
+#[allow(dead_code)]
+pub const SUPPORTS_FASTOPEN: bool = cfg!(any(
+    target_os = "linux", target_os = "android", target_os = "freebsd", target_os = "macos", target_os = "windows"
+));
+// TCP_CORK, holding back partial segments until uncorked
+pub const SUPPORTS_CORK: bool = cfg!(any(target_os = "linux", target_os = "android", target_os = "fuchsia"));
+// linux keeps half of SO_RCVBUF and SO_SNDBUF for bookkeeping and doubles
+// whatever is requested
+pub const DOUBLES_SOCKET_BUFFERS: bool = cfg!(any(target_os = "linux", target_os = "android"));
+
+// MSG_OOB compiles everywhere but can still be refused, e.g. by wsl1 or a
+// seccomp filter. probed once over loopback, the first call blocks briefly
+// and is made at startup, before any connection is handled
+pub fn check_oob_support() -> bool {
+    static SUPPORTED: OnceLock<bool> = OnceLock::new();
+    *SUPPORTED.get_or_init(|| match probe_oob() {
+        Ok(()) => true,
+        Err(err) => {
+            warn!("tcp urgent data unsupported ({err}), oob methods fall back to plain splits");
+            false
+        }
+    })
+}
+
+fn probe_oob() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let stream = TcpStream::connect(listener.local_addr()?)?;
+    let _peer = listener.accept()?;
+    SockRef::from(&stream).send_out_of_band(b"a")?;
+    Ok(())
+}
+
+pub fn send_oob<'a>(socket: impl Into<SockRef<'a>>, data: &[u8]) -> Result<usize> {
+    socket.into().send_out_of_band(data)
+}
+
+// there is no socket option for the window scale itself. linux and the bsds
+// pick the smallest scale that fits the receive buffer as it is when the SYN
+// goes out. the buffer stays this size for the whole connection, so a small
+// scale also caps the receive window and with it the download throughput.
+// linux caps the size at net.core.rmem_max
+pub fn set_window_scale<'a>(socket: impl Into<SockRef<'a>>, scale: u8) -> Result<()> {
+    let size = 65535usize << scale;
+    let size = if DOUBLES_SOCKET_BUFFERS { size / 2 } else { size };
+    socket.into().set_recv_buffer_size(size)
+}
+
+// the first probe goes out after `idle` without traffic, the next ones
+// `idle` apart. windows and older macos ignore the interval
+pub fn set_keepalive<'a>(socket: impl Into<SockRef<'a>>, idle: Duration) -> Result<()> {
+    let keepalive = TcpKeepalive::new().with_time(idle).with_interval(idle);
+    socket.into().set_tcp_keepalive(&keepalive)
+}
+
+// the kernel splits writes into segments that fit the send buffer. linux
+// doubles the requested value to leave room for bookkeeping
+pub fn set_send_buffer_size<'a>(socket: impl Into<SockRef<'a>>, size: usize) -> Result<()> {
+    socket.into().set_send_buffer_size(size)
+}
+
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "fuchsia"))]
+pub fn set_cork<'a>(socket: impl Into<SockRef<'a>>, cork: bool) -> Result<()> {
+    socket.into().set_cork(cork)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "fuchsia")))]
+pub fn set_cork<'a>(_socket: impl Into<SockRef<'a>>, _cork: bool) -> Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "TCP_CORK is not supported on this platform"))
+}
+
+// TCP_MAXSEG before connect caps the mss option in the SYN
+#[cfg(unix)]
+pub fn set_mss<'a>(socket: impl Into<SockRef<'a>>, mss: u32) -> Result<()> {
+    socket.into().set_mss(mss)
+}
+
+#[cfg(not(unix))]
+pub fn set_mss<'a>(_socket: impl Into<SockRef<'a>>, _mss: u32) -> Result<()> {
+    Err(std::io::Error::new(std::io::ErrorKind::Unsupported, "TCP_MAXSEG is not supported on this platform"))
+}
+
+// linux has no socket option that turns tcp timestamps off, TCP_TIMESTAMP
+// only moves their offset and needs TCP_REPAIR. None where the system wide
+// setting can't be read
+pub fn tcp_timestamps_enabled() -> Option<bool> {
+    #[cfg(target_os = "linux")]
+    {
+        let value = std::fs::read_to_string("/proc/sys/net/ipv4/tcp_timestamps").ok()?;
+        Some(value.trim() != "0")
+    }
+    #[cfg(not(target_os = "linux"))]
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn send_buffer_size_reads_back() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        set_send_buffer_size(&stream, 32 * 1024).unwrap();
+        let expected = if DOUBLES_SOCKET_BUFFERS { 64 * 1024 } else { 32 * 1024 };
+        assert_eq!(SockRef::from(&stream).send_buffer_size().unwrap(), expected);
+    }
+
+    #[cfg(target_os = "linux")]
+    #[test]
+    fn keepalive_reads_back() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        set_keepalive(&stream, Duration::from_secs(30)).unwrap();
+        let socket = SockRef::from(&stream);
+        assert!(socket.keepalive().unwrap());
+        assert_eq!(socket.keepalive_time().unwrap(), Duration::from_secs(30));
+        assert_eq!(socket.keepalive_interval().unwrap(), Duration::from_secs(30));
+    }
+
+    #[test]
+    fn window_scale_sets_the_receive_buffer() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let stream = TcpStream::connect(listener.local_addr().unwrap()).unwrap();
+        set_window_scale(&stream, 1).unwrap();
+        assert_eq!(SockRef::from(&stream).recv_buffer_size().unwrap(), 65535 << 1);
+    }
+}
@@ -0,0 +1,179 @@
+use crate::happy_eyeballs::{connect_happy_eyeballs, ConnectOptions};
+use dashmap::DashMap;
+use std::{
+    io::{ErrorKind, Result},
+    sync::{Arc, Weak},
+    time::Duration,
+};
+use tokio::{
+    net::TcpStream,
+    time::{self, Instant},
+};
+use tracing::debug;
+
+// servers close idle connections on their own, usually after a minute or two
+const IDLE_TTL: Duration = Duration::from_secs(60);
+const REAP_INTERVAL: Duration = Duration::from_secs(10);
+// targets beyond this are connected to directly and not pooled
+const MAX_TARGETS: usize = 256;
+
+// keeps up to `size` sockets connected ahead of time to every target that
+// was connected to before, saving the handshake on the next connection.
+// sockets are never returned: once a client session ran over one, the
+// server holds that session's tls or http state and the stream can't be
+// handed to another client, so a taken socket is replaced by a fresh one
+#[derive(Debug)]
+pub struct PreconnectPool {
+    idle: DashMap<(String, u16), Vec<Idle>>,
+    size: usize,
+    options: ConnectOptions,
+}
+
+#[derive(Debug)]
+struct Idle {
+    stream: TcpStream,
+    since: Instant,
+}
+
+impl Idle {
+    fn is_usable(&self, now: Instant) -> bool {
+        now - self.since <= IDLE_TTL && is_healthy(&self.stream)
+    }
+}
+
+impl PreconnectPool {
+    pub fn new(size: usize, options: ConnectOptions) -> Arc<Self> {
+        let pool = Arc::new(PreconnectPool { idle: DashMap::new(), size, options });
+        tokio::spawn(reap(Arc::downgrade(&pool)));
+        pool
+    }
+
+    pub async fn connect(self: &Arc<Self>, host: &str, port: u16) -> Result<TcpStream> {
+        let key = (host.to_owned(), port);
+        let pooled = self.take(&key);
+        tokio::spawn(self.clone().refill(key));
+        match pooled {
+            Some(stream) => Ok(stream),
+            None => connect_happy_eyeballs(host, port, self.options).await,
+        }
+    }
+
+    fn take(&self, key: &(String, u16)) -> Option<TcpStream> {
+        let now = Instant::now();
+        let mut idle = self.idle.get_mut(key)?;
+        while let Some(pooled) = idle.pop() {
+            if pooled.is_usable(now) {
+                return Some(pooled.stream);
+            }
+        }
+        None
+    }
+
+    async fn refill(self: Arc<Self>, key: (String, u16)) {
+        if !self.idle.contains_key(&key) && self.idle.len() >= MAX_TARGETS {
+            debug!(host = key.0, port = key.1, "pool full, target not pooled");
+            return;
+        }
+        while self.idle.get(&key).map_or(0, |idle| idle.len()) < self.size {
+            match connect_happy_eyeballs(&key.0, key.1, self.options).await {
+                Ok(stream) => {
+                    let mut idle = self.idle.entry(key.clone()).or_default();
+                    // concurrent refills for the same target can overshoot
+                    if idle.len() >= self.size {
+                        return;
+                    }
+                    idle.push(Idle { stream, since: Instant::now() });
+                }
+                Err(err) => {
+                    debug!(host = key.0, port = key.1, "pool connect failed: {err}");
+                    return;
+                }
+            }
+        }
+    }
+}
+
+// closes expired and dead sockets and forgets targets left without any, so
+// they don't count against MAX_TARGETS
+async fn reap(pool: Weak<PreconnectPool>) {
+    let mut interval = time::interval(REAP_INTERVAL);
+    loop {
+        interval.tick().await;
+        let Some(pool) = pool.upgrade() else {
+            return;
+        };
+        let now = Instant::now();
+        pool.idle.retain(|_, idle| {
+            idle.retain(|pooled| pooled.is_usable(now));
+            !idle.is_empty()
+        });
+    }
+}
+
+// an idle upstream has nothing to say, eof or unsolicited data means the
+// server closed or is about to close it
+fn is_healthy(stream: &TcpStream) -> bool {
+    matches!(stream.try_read(&mut [0; 1]), Err(err) if err.kind() == ErrorKind::WouldBlock)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use tokio::net::TcpListener;
+
+    async fn pooled_count(pool: &PreconnectPool, key: &(String, u16)) -> usize {
+        // the refill runs in the background
+        for _ in 0..100 {
+            if pool.idle.get(key).is_some_and(|idle| !idle.is_empty()) {
+                break;
+            }
+            time::sleep(Duration::from_millis(10)).await;
+        }
+        pool.idle.get(key).map_or(0, |idle| idle.len())
+    }
+
+    #[tokio::test]
+    async fn closed_socket_is_not_handed_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let pool = PreconnectPool::new(1, ConnectOptions::default());
+        let key = ("127.0.0.1".to_owned(), port);
+
+        let _first = pool.connect("127.0.0.1", port).await.unwrap();
+        assert_eq!(pooled_count(&pool, &key).await, 1);
+        // the server closes both the direct and the pooled connection
+        drop((listener.accept().await.unwrap(), listener.accept().await.unwrap()));
+        time::sleep(Duration::from_millis(50)).await;
+
+        assert!(pool.take(&key).is_none());
+    }
+
+    #[tokio::test]
+    async fn expired_socket_is_not_handed_out() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let pool = PreconnectPool::new(1, ConnectOptions::default());
+        let key = ("127.0.0.1".to_owned(), port);
+
+        let _first = pool.connect("127.0.0.1", port).await.unwrap();
+        let _accepted = (listener.accept().await.unwrap(), listener.accept().await.unwrap());
+        assert_eq!(pooled_count(&pool, &key).await, 1);
+        pool.idle.get_mut(&key).unwrap()[0].since -= IDLE_TTL + Duration::from_secs(1);
+
+        assert!(pool.take(&key).is_none());
+    }
+
+    #[tokio::test]
+    async fn targets_beyond_the_cap_are_not_pooled() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let port = listener.local_addr().unwrap().port();
+        let pool = PreconnectPool::new(1, ConnectOptions::default());
+        for fake_port in 0..MAX_TARGETS as u16 {
+            pool.idle.insert((format!("target{fake_port}"), fake_port), Vec::new());
+        }
+
+        pool.clone().refill(("127.0.0.1".to_owned(), port)).await;
+
+        assert_eq!(pool.idle.len(), MAX_TARGETS);
+    }
+}
@@ -0,0 +1,66 @@
+use crate::{benchmark::synthetic_client_hello, desync_hello_phrase, happy_eyeballs::connect_happy_eyeballs, Method, Params};
+use std::{io::Result, time::Duration};
+use tokio::{
+    io::AsyncReadExt,
+    time::{timeout, Instant},
+};
+use tracing::info;
+
+// per attempt, a dpi that drops the connection silently shows up as a timeout
+const PROBE_TIMEOUT: Duration = Duration::from_secs(5);
+
+// tried in order after a plain connection, the most common working ones first
+const CANDIDATES: [&str; 6] = [
+    "split:1",
+    "split:-1:sni",
+    "disorder:1",
+    "disorder:-1:sni",
+    "oob:-1:sni",
+    "fragment-cipher:0",
+];
+
+#[derive(Debug)]
+pub struct ProbeResult {
+    pub success: bool,
+    // None with success when no desync is needed
+    pub method_applied: Option<Method>,
+    pub latency_ms: u64,
+}
+
+// sends a ClientHello for `target` with one method at a time and stops at
+// the first one the server answers. any tls record back counts, an alert
+// for the synthetic hello means it got past the dpi all the same
+pub async fn probe_dpi(target: &str, port: u16, params: &Params) -> ProbeResult {
+    let candidates = std::iter::once(None)
+        .chain(CANDIDATES.into_iter().map(|spec| Some(Method::try_from(spec).expect("valid method spec"))));
+    for method in candidates {
+        let start = Instant::now();
+        let answered = timeout(PROBE_TIMEOUT, attempt(target, port, method.clone(), params)).await;
+        let latency_ms = start.elapsed().as_millis() as u64;
+        let success = matches!(answered, Ok(Ok(true)));
+        let method_name = method.as_ref().map_or("none".to_owned(), Method::to_string);
+        info!(method = method_name, success, latency_ms, "probe");
+        if success {
+            return ProbeResult { success, method_applied: method, latency_ms };
+        }
+    }
+    ProbeResult { success: false, method_applied: None, latency_ms: 0 }
+}
+
+async fn attempt(target: &str, port: u16, method: Option<Method>, params: &Params) -> Result<bool> {
+    // only the method under test, no tlsrec or strategy rules
+    let mut params = params.clone();
+    params.methods = method.into_iter().collect();
+    params.tlsrec = None;
+    params.strategy.clear();
+    params.desync_ports = None;
+    params.skip_ports.clear();
+
+    let mut stream = connect_happy_eyeballs(target, port, params.connect_options()).await?;
+    let hello = synthetic_client_hello(target, 0);
+    desync_hello_phrase(&mut &hello[..], &mut stream, port, params).await?;
+    let mut record_type = [0; 1];
+    let n = stream.read(&mut record_type).await?;
+    // handshake or alert
+    Ok(n == 1 && matches!(record_type[0], 0x15 | 0x16))
+}
@@ -0,0 +1,111 @@
+use serde::{Deserialize, Serialize};
+use std::net::{IpAddr, Ipv6Addr, SocketAddr};
+
+const V2_SIGNATURE: [u8; 12] = *b"\r\n\r\n\0\r\nQUIT\n";
+
+// header sent to the upstream ahead of any client data so it sees the
+// client address instead of ours
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum ProxyProtocol {
+    V1,
+    V2,
+}
+
+impl ProxyProtocol {
+    pub fn encode(self, src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+        match self {
+            ProxyProtocol::V1 => encode_proxy_protocol_v1(src, dst),
+            ProxyProtocol::V2 => encode_proxy_protocol_v2(src, dst),
+        }
+    }
+}
+
+// `PROXY TCP4 <src ip> <dst ip> <src port> <dst port>\r\n`. a connection
+// between two address families has no v1 form and is sent as UNKNOWN
+pub fn encode_proxy_protocol_v1(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let family = match (src, dst) {
+        (SocketAddr::V4(_), SocketAddr::V4(_)) => "TCP4",
+        (SocketAddr::V6(_), SocketAddr::V6(_)) => "TCP6",
+        _ => return b"PROXY UNKNOWN\r\n".to_vec(),
+    };
+    format!("PROXY {family} {} {} {} {}\r\n", src.ip(), dst.ip(), src.port(), dst.port()).into_bytes()
+}
+
+// signature, version 2 PROXY command, family and the address block. a
+// connection between two address families uses ipv4-mapped ipv6 addresses
+pub fn encode_proxy_protocol_v2(src: SocketAddr, dst: SocketAddr) -> Vec<u8> {
+    let mut header = V2_SIGNATURE.to_vec();
+    header.push(0x21);
+    match (src.ip(), dst.ip()) {
+        (IpAddr::V4(src_ip), IpAddr::V4(dst_ip)) => {
+            // TCP over IPv4
+            header.push(0x11);
+            header.extend_from_slice(&12u16.to_be_bytes());
+            header.extend_from_slice(&src_ip.octets());
+            header.extend_from_slice(&dst_ip.octets());
+        }
+        (src_ip, dst_ip) => {
+            // TCP over IPv6
+            header.push(0x21);
+            header.extend_from_slice(&36u16.to_be_bytes());
+            header.extend_from_slice(&to_ipv6(src_ip).octets());
+            header.extend_from_slice(&to_ipv6(dst_ip).octets());
+        }
+    }
+    header.extend_from_slice(&src.port().to_be_bytes());
+    header.extend_from_slice(&dst.port().to_be_bytes());
+    header
+}
+
+fn to_ipv6(ip: IpAddr) -> Ipv6Addr {
+    match ip {
+        IpAddr::V4(ip) => ip.to_ipv6_mapped(),
+        IpAddr::V6(ip) => ip,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the spec's signature, 0x21 for version 2 PROXY
+    const PREFIX: [u8; 13] = [0x0d, 0x0a, 0x0d, 0x0a, 0x00, 0x0d, 0x0a, 0x51, 0x55, 0x49, 0x54, 0x0a, 0x21];
+
+    #[test]
+    fn v2_ipv4() {
+        let src: SocketAddr = "192.0.2.1:56324".parse().unwrap();
+        let dst: SocketAddr = "198.51.100.7:443".parse().unwrap();
+        let mut expected = PREFIX.to_vec();
+        expected.extend_from_slice(&[
+            0x11, 0x00, 0x0c,
+            192, 0, 2, 1,
+            198, 51, 100, 7,
+            0xdc, 0x04,
+            0x01, 0xbb,
+        ]);
+        assert_eq!(encode_proxy_protocol_v2(src, dst), expected);
+    }
+
+    #[test]
+    fn v2_ipv6() {
+        let src: SocketAddr = "[2001:db8::1]:56324".parse().unwrap();
+        let dst: SocketAddr = "[2001:db8::2]:443".parse().unwrap();
+        let mut expected = PREFIX.to_vec();
+        expected.extend_from_slice(&[0x21, 0x00, 0x24]);
+        expected.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x01]);
+        expected.extend_from_slice(&[0x20, 0x01, 0x0d, 0xb8, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0x02]);
+        expected.extend_from_slice(&[0xdc, 0x04, 0x01, 0xbb]);
+        assert_eq!(encode_proxy_protocol_v2(src, dst), expected);
+    }
+
+    #[test]
+    fn v2_mixed_families_use_mapped_addresses() {
+        let src: SocketAddr = "192.0.2.1:56324".parse().unwrap();
+        let dst: SocketAddr = "[2001:db8::2]:443".parse().unwrap();
+        let header = encode_proxy_protocol_v2(src, dst);
+        assert_eq!(header[13..16], [0x21, 0x00, 0x24]);
+        assert_eq!(header[16..32], [0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0xff, 0xff, 192, 0, 2, 1]);
+        assert_eq!(header.len(), 16 + 36);
+    }
+}
@@ -0,0 +1,98 @@
+use std::{
+    io::{Error, ErrorKind, Result},
+    time::Duration,
+};
+use tokio::{
+    io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::timeout,
+};
+use tracing::debug;
+
+// how long a half duplex relay waits for the upstream to finish its answer
+const HALF_DUPLEX_TIMEOUT: Duration = Duration::from_secs(60);
+
+// like copy_bidirectional, but each direction runs until its own end. when
+// one side closes or resets, the other side gets a shutdown (fin) of its
+// write half instead of being dropped with data in flight. returns the
+// bytes copied from a to b and from b to a
+pub async fn relay_bidirectional<A, B>(a: &mut A, b: &mut B) -> Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+{
+    relay_bidirectional_with(a, b, |_| None).await
+}
+
+// `rewrite` sees every read from b and returns a replacement for it, if any
+pub async fn relay_bidirectional_with<A, B, F>(a: &mut A, b: &mut B, rewrite: F) -> Result<(u64, u64)>
+where
+    A: AsyncRead + AsyncWrite + Unpin,
+    B: AsyncRead + AsyncWrite + Unpin,
+    F: FnMut(&[u8]) -> Option<Vec<u8>>,
+{
+    let (mut a_read, mut a_write) = io::split(a);
+    let (mut b_read, mut b_write) = io::split(b);
+    let (a_to_b, b_to_a) = tokio::join!(
+        relay_one(&mut a_read, &mut b_write, |_| None),
+        relay_one(&mut b_read, &mut a_write, rewrite)
+    );
+    Ok((a_to_b?, b_to_a?))
+}
+
+// b to a only, for one-shot request-response protocols. whatever a sends
+// after the hello is never read. returns the bytes copied from b to a
+pub async fn relay_half_duplex<A, B, F>(a: &mut A, b: &mut B, rewrite: F) -> Result<u64>
+where
+    A: AsyncWrite + Unpin,
+    B: AsyncRead + Unpin,
+    F: FnMut(&[u8]) -> Option<Vec<u8>>,
+{
+    match timeout(HALF_DUPLEX_TIMEOUT, relay_one(b, a, rewrite)).await {
+        Ok(copied) => copied,
+        Err(_) => Err(Error::new(ErrorKind::TimedOut, "upstream answer not complete in time")),
+    }
+}
+
+// a disconnect still reports the bytes copied before it
+async fn relay_one<R, W, F>(reader: &mut R, writer: &mut W, mut rewrite: F) -> Result<u64>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+    F: FnMut(&[u8]) -> Option<Vec<u8>>,
+{
+    let mut buf = vec![0; 16384];
+    let mut copied = 0;
+    let result = loop {
+        let n = match reader.read(&mut buf).await {
+            Ok(0) => break Ok(()),
+            Ok(n) => n,
+            Err(err) => break Err(err),
+        };
+        let rewritten = rewrite(&buf[..n]);
+        let data = rewritten.as_deref().unwrap_or(&buf[..n]);
+        if let Err(err) = writer.write_all(data).await {
+            break Err(err);
+        }
+        copied += data.len() as u64;
+    };
+    let _ = writer.shutdown().await;
+    match result {
+        Ok(()) => Ok(copied),
+        Err(err) if is_disconnect(err.kind()) => {
+            debug!("relay direction closed: {err}");
+            Ok(copied)
+        }
+        Err(err) => Err(err),
+    }
+}
+
+fn is_disconnect(kind: ErrorKind) -> bool {
+    matches!(
+        kind,
+        ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::BrokenPipe
+            | ErrorKind::UnexpectedEof
+            | ErrorKind::WriteZero
+    )
+}
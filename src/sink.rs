@@ -0,0 +1,43 @@
+use crate::{platform, Method};
+use std::{io::Result, net::SocketAddr};
+use tokio::{io::AsyncWrite, net::TcpStream};
+
+pub trait TtlControl {
+    fn ttl(&self) -> Result<u32>;
+    fn set_ttl(&self, ttl: u32) -> Result<()>;
+}
+
+// the upstream side of desync. everything but the writes themselves goes
+// through here, so desync isn't tied to a real TcpStream
+pub trait DesyncSink: AsyncWrite + TtlControl + Unpin {
+    fn send_oob(&self, data: &[u8]) -> Result<usize>;
+    fn set_cork(&self, cork: bool) -> Result<()>;
+    fn peer_addr(&self) -> Result<SocketAddr>;
+    // called before the writes of each method, None before the rest of the
+    // buffer. only sinks that record what they send care
+    fn begin_method(&mut self, _method: Option<&Method>) {}
+}
+
+impl TtlControl for TcpStream {
+    fn ttl(&self) -> Result<u32> {
+        TcpStream::ttl(self)
+    }
+
+    fn set_ttl(&self, ttl: u32) -> Result<()> {
+        TcpStream::set_ttl(self, ttl)
+    }
+}
+
+impl DesyncSink for TcpStream {
+    fn send_oob(&self, data: &[u8]) -> Result<usize> {
+        platform::send_oob(self, data)
+    }
+
+    fn set_cork(&self, cork: bool) -> Result<()> {
+        platform::set_cork(self, cork)
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        TcpStream::peer_addr(self)
+    }
+}
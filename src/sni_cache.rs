@@ -0,0 +1,54 @@
+use dashmap::DashMap;
+use std::{net::SocketAddr, time::Duration};
+use tokio::time::Instant;
+
+const MAX_AGE: Duration = Duration::from_secs(5 * 60);
+
+// remembers the sni last seen for an ip target so connections made by
+// address can be logged with a hostname
+#[derive(Debug)]
+pub struct SniCache {
+    entries: DashMap<SocketAddr, Entry>,
+    capacity: usize,
+}
+
+#[derive(Debug)]
+struct Entry {
+    sni: String,
+    last_used: Instant,
+}
+
+impl SniCache {
+    pub fn new(capacity: usize) -> Self {
+        SniCache { entries: DashMap::new(), capacity }
+    }
+
+    pub fn get(&self, addr: &SocketAddr) -> Option<String> {
+        let now = Instant::now();
+        let mut entry = self.entries.get_mut(addr)?;
+        if now - entry.last_used > MAX_AGE {
+            drop(entry);
+            self.entries.remove(addr);
+            return None;
+        }
+        entry.last_used = now;
+        Some(entry.sni.clone())
+    }
+
+    pub fn insert(&self, addr: SocketAddr, sni: String) {
+        let now = Instant::now();
+        if !self.entries.contains_key(&addr) && self.entries.len() >= self.capacity {
+            self.entries.retain(|_, entry| now - entry.last_used <= MAX_AGE);
+            // still full, drop the least recently used entry
+            if self.entries.len() >= self.capacity {
+                let oldest = self.entries.iter()
+                    .min_by_key(|entry| entry.last_used)
+                    .map(|entry| *entry.key());
+                if let Some(oldest) = oldest {
+                    self.entries.remove(&oldest);
+                }
+            }
+        }
+        self.entries.insert(addr, Entry { sni, last_used: now });
+    }
+}
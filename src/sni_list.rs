@@ -0,0 +1,59 @@
+use std::{
+    collections::HashSet,
+    fs,
+    io::Result as IoResult,
+    path::{Path, PathBuf},
+    sync::{Arc, RwLock},
+};
+use tracing::{error, info};
+
+// `--sni-list-file`: the only server names that get desync, matched exactly
+// and case-insensitively. reloaded on SIGHUP
+#[derive(Debug)]
+pub struct SniList {
+    path: PathBuf,
+    hosts: RwLock<HashSet<String>>,
+}
+
+impl SniList {
+    pub fn load(path: PathBuf) -> IoResult<Arc<Self>> {
+        let hosts = RwLock::new(read_hosts(&path)?);
+        let list = Arc::new(SniList { path, hosts });
+        #[cfg(unix)]
+        list.clone().reload_on_sighup()?;
+        Ok(list)
+    }
+
+    #[cfg(unix)]
+    fn reload_on_sighup(self: Arc<Self>) -> IoResult<()> {
+        use tokio::signal::unix::{signal, SignalKind};
+
+        let mut hangup = signal(SignalKind::hangup())?;
+        tokio::spawn(async move {
+            while hangup.recv().await.is_some() {
+                match read_hosts(&self.path) {
+                    Ok(hosts) => {
+                        info!("reloaded {} sni hosts from {}", hosts.len(), self.path.display());
+                        *self.hosts.write().expect("sni list lock poisoned") = hosts;
+                    }
+                    Err(err) => error!("failed to reload {}: {err}", self.path.display()),
+                }
+            }
+        });
+        Ok(())
+    }
+
+    pub fn contains(&self, sni: &str) -> bool {
+        self.hosts.read().expect("sni list lock poisoned").contains(&sni.to_ascii_lowercase())
+    }
+}
+
+// one hostname per line, empty lines and `#` comments are skipped
+fn read_hosts(path: &Path) -> IoResult<HashSet<String>> {
+    Ok(fs::read_to_string(path)?
+        .lines()
+        .map(str::trim)
+        .filter(|line| !line.is_empty() && !line.starts_with('#'))
+        .map(str::to_ascii_lowercase)
+        .collect())
+}
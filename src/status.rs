@@ -0,0 +1,60 @@
+use crate::metrics::Stats;
+use std::{
+    io::{Error, ErrorKind, Result},
+    path::{Path, PathBuf},
+};
+
+// every client of the status socket gets one json `Stats` snapshot, then
+// the connection is closed
+#[cfg(unix)]
+pub async fn serve(path: PathBuf) -> Result<()> {
+    use std::os::unix::fs::FileTypeExt;
+    use tokio::{io::AsyncWriteExt, net::UnixListener, time::Instant};
+
+    // a socket left behind by a previous run, anything else is not ours to remove
+    if std::fs::symlink_metadata(&path).is_ok_and(|meta| meta.file_type().is_socket()) {
+        std::fs::remove_file(&path)?;
+    }
+    let listener = UnixListener::bind(&path)?;
+    let start = Instant::now();
+    loop {
+        let (mut stream, _) = listener.accept().await?;
+        let snapshot = serde_json::to_vec(&Stats::snapshot(start.elapsed()))?;
+        tokio::spawn(async move {
+            let _ = stream.write_all(&snapshot).await;
+        });
+    }
+}
+
+#[cfg(not(unix))]
+pub async fn serve(_path: PathBuf) -> Result<()> {
+    Err(Error::new(ErrorKind::Unsupported, "the status socket needs unix domain sockets"))
+}
+
+// `--status`: reads a snapshot from a running instance and prints it as a table
+#[cfg(unix)]
+pub async fn print(path: &Path) -> Result<()> {
+    use tokio::{io::AsyncReadExt, net::UnixStream};
+
+    let mut stream = UnixStream::connect(path).await?;
+    let mut json = Vec::new();
+    stream.read_to_end(&mut json).await?;
+    let stats: Stats = serde_json::from_slice(&json).map_err(|err| Error::new(ErrorKind::InvalidData, err))?;
+    let rows = [
+        ("uptime", format!("{}s", stats.uptime_secs)),
+        ("active", stats.active.to_string()),
+        ("connections", stats.connections.to_string()),
+        ("errors", stats.errors.to_string()),
+        ("bytes in", stats.bytes_in.to_string()),
+        ("bytes out", stats.bytes_out.to_string()),
+    ];
+    for (name, value) in rows {
+        println!("{name:<12} {value:>14}");
+    }
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub async fn print(_path: &Path) -> Result<()> {
+    Err(Error::new(ErrorKind::Unsupported, "the status socket needs unix domain sockets"))
+}
@@ -0,0 +1,284 @@
+use crate::{Flag, Method, Part, Protocol};
+use serde::{Deserialize, Serialize};
+use std::fmt;
+
+// one rule per line, `#` starts a comment:
+//
+//   IF tls THEN tlsrec AT sni; split AT 1; disorder AT sni+1
+//   IF http THEN split AT host
+//   disorder AT 1
+//
+// a rule without IF applies to any protocol. the first matching rule
+// replaces the methods and tlsrec given on the command line
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct StrategyRule {
+    pub predicate: Predicate,
+    pub tlsrec: Option<Part>,
+    pub methods: Vec<Method>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Predicate {
+    Tls,
+    Http,
+    Any,
+}
+
+impl Predicate {
+    pub fn matches(self, protocol: &Protocol) -> bool {
+        match self {
+            Predicate::Tls => *protocol == Protocol::Tls,
+            Predicate::Http => *protocol == Protocol::Http,
+            Predicate::Any => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct StrategyError {
+    line: usize,
+    message: String,
+}
+
+impl fmt::Display for StrategyError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "line {}: {}", self.line, self.message)
+    }
+}
+
+impl std::error::Error for StrategyError {}
+
+pub fn parse_strategy(source: &str) -> Result<Vec<StrategyRule>, StrategyError> {
+    let mut rules = Vec::new();
+    for (i, line) in source.lines().enumerate() {
+        let line = line.split('#').next().unwrap_or_default();
+        let tokens = tokenize(line);
+        if tokens.is_empty() {
+            continue;
+        }
+        let mut parser = Parser { tokens, pos: 0 };
+        let rule = parser.rule().map_err(|message| StrategyError { line: i + 1, message })?;
+        rules.push(rule);
+    }
+    Ok(rules)
+}
+
+fn tokenize(line: &str) -> Vec<&str> {
+    let mut tokens = Vec::new();
+    let mut start = None;
+    for (i, c) in line.char_indices() {
+        if c.is_whitespace() || c == ';' || c == '+' {
+            if let Some(s) = start.take() {
+                tokens.push(&line[s..i]);
+            }
+            if !c.is_whitespace() {
+                tokens.push(&line[i..i + 1]);
+            }
+        } else if start.is_none() {
+            start = Some(i);
+        }
+    }
+    if let Some(s) = start {
+        tokens.push(&line[s..]);
+    }
+    tokens
+}
+
+struct Parser<'a> {
+    tokens: Vec<&'a str>,
+    pos: usize,
+}
+
+impl<'a> Parser<'a> {
+    fn peek(&self) -> Option<&'a str> {
+        self.tokens.get(self.pos).copied()
+    }
+
+    fn next(&mut self) -> Result<&'a str, String> {
+        let token = self.peek().ok_or("unexpected end of rule")?;
+        self.pos += 1;
+        Ok(token)
+    }
+
+    fn keyword(&mut self, keyword: &str) -> Result<(), String> {
+        match self.next()? {
+            token if token.eq_ignore_ascii_case(keyword) => Ok(()),
+            token => Err(format!("expected {keyword}, got `{token}`")),
+        }
+    }
+
+    // rule := [IF predicate THEN] action (";" action)*
+    fn rule(&mut self) -> Result<StrategyRule, String> {
+        let predicate = if self.peek().is_some_and(|t| t.eq_ignore_ascii_case("if")) {
+            self.pos += 1;
+            let predicate = self.predicate()?;
+            self.keyword("then")?;
+            predicate
+        } else {
+            Predicate::Any
+        };
+
+        let mut rule = StrategyRule { predicate, tlsrec: None, methods: Vec::new() };
+        loop {
+            self.action(&mut rule)?;
+            match self.peek() {
+                None => return Ok(rule),
+                Some(";") => self.pos += 1,
+                Some(token) => return Err(format!("expected `;`, got `{token}`")),
+            }
+            // allow a trailing `;`
+            if self.peek().is_none() {
+                return Ok(rule);
+            }
+        }
+    }
+
+    // predicate := tls | http | any
+    fn predicate(&mut self) -> Result<Predicate, String> {
+        match self.next()? {
+            "tls" => Ok(Predicate::Tls),
+            "http" => Ok(Predicate::Http),
+            "any" => Ok(Predicate::Any),
+            token => Err(format!("unknown protocol `{token}`")),
+        }
+    }
+
+    // action := method AT position
+    fn action(&mut self, rule: &mut StrategyRule) -> Result<(), String> {
+        let kind = self.next()?;
+        self.keyword("at")?;
+        let part = self.position()?;
+        let method = match kind {
+            "tlsrec" => {
+                rule.tlsrec = Some(part);
+                return Ok(());
+            }
+            "split" => Method::Split(part),
+            "disorder" => Method::Disorder(part),
+            "fragment-cipher" => Method::FragmentCipher(part),
+            "oob" => Method::Oob(part, 1),
+            "oob-window" => Method::OobUrgent(part),
+            "http-url-split" => Method::HttpFragmentUrl(part),
+            _ => return Err(format!("unknown method `{kind}`")),
+        };
+        rule.methods.push(method);
+        Ok(())
+    }
+
+    // position := number | base ["+" number]
+    // base := sni | sni-end | host | cipher | header=<name>
+    fn position(&mut self) -> Result<Part, String> {
+        let token = self.next()?;
+        if let Ok(pos) = token.parse() {
+            return Ok(Part::absolute(pos));
+        }
+        let flag = match token {
+            "sni" => Flag::OffsetSni,
+            "sni-end" => Flag::OffsetSniEnd,
+            "host" => Flag::OffsetHost,
+            "cipher" => Flag::OffsetCipher,
+            _ => match token.strip_prefix("header=") {
+                Some(name) if !name.is_empty() => Flag::OffsetHeader(name.to_owned()),
+                _ => return Err(format!("invalid position `{token}`")),
+            },
+        };
+        let mut pos = 0;
+        if self.peek() == Some("+") {
+            self.pos += 1;
+            let offset = self.next()?;
+            pos = offset.parse().map_err(|_| format!("invalid offset `{offset}`"))?;
+        }
+        Ok(Part { pos, offset: 0, flag: Some(flag) })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn error(source: &str) -> String {
+        parse_strategy(source).unwrap_err().to_string()
+    }
+
+    #[test]
+    fn parses_rules_per_protocol() {
+        let rules = parse_strategy("IF tls THEN tlsrec AT sni; split AT 1; disorder AT sni+1\nIF http THEN split AT host\ndisorder AT 1").unwrap();
+        assert_eq!(rules.len(), 3);
+
+        assert_eq!(rules[0].predicate, Predicate::Tls);
+        assert_eq!(rules[0].tlsrec, Some(Part::with_sni_flag(0)));
+        assert_eq!(rules[0].methods, [Method::Split(Part::absolute(1)), Method::Disorder(Part::with_sni_flag(1))]);
+
+        assert_eq!(rules[1].predicate, Predicate::Http);
+        assert_eq!(rules[1].tlsrec, None);
+        assert_eq!(rules[1].methods, [Method::Split(Part::with_host_flag(0))]);
+
+        // no IF applies to any protocol
+        assert_eq!(rules[2].predicate, Predicate::Any);
+        assert_eq!(rules[2].methods, [Method::Disorder(Part::absolute(1))]);
+    }
+
+    #[test]
+    fn parses_every_method_and_position() {
+        let rules = parse_strategy(
+            "fragment-cipher AT cipher; oob AT 2; oob-window AT sni-end + 3; http-url-split AT header=Host",
+        )
+        .unwrap();
+        assert_eq!(rules[0].methods, [
+            Method::FragmentCipher(Part::with_cipher_flag(0)),
+            Method::Oob(Part::absolute(2), 1),
+            Method::OobUrgent(Part::with_sni_end_flag(3)),
+            Method::HttpFragmentUrl(Part::with_header_flag(0, "Host".to_owned())),
+        ]);
+    }
+
+    #[test]
+    fn skips_comments_blank_lines_and_a_trailing_semicolon() {
+        let rules = parse_strategy("# tls first\n\n  if tls then split at 1;  # keywords in any case\n").unwrap();
+        assert_eq!(rules.len(), 1);
+        assert_eq!(rules[0].predicate, Predicate::Tls);
+        assert_eq!(rules[0].methods, [Method::Split(Part::absolute(1))]);
+        assert!(parse_strategy("# nothing\n").unwrap().is_empty());
+    }
+
+    #[test]
+    fn first_matching_rule_wins() {
+        let rules = parse_strategy("IF http THEN split AT host\nIF tls THEN split AT sni\nsplit AT 1\nIF tls THEN disorder AT 1").unwrap();
+        let pick = |protocol: Protocol| rules.iter().find(|rule| rule.predicate.matches(&protocol)).unwrap();
+        assert_eq!(pick(Protocol::Http).methods, [Method::Split(Part::with_host_flag(0))]);
+        assert_eq!(pick(Protocol::Tls).methods, [Method::Split(Part::with_sni_flag(0))]);
+        // a catch-all shadows the rules after it
+        assert_eq!(pick(Protocol::Other).methods, [Method::Split(Part::absolute(1))]);
+    }
+
+    #[test]
+    fn offset_binds_to_the_position_before_it() {
+        let rules = parse_strategy("split AT sni+1; disorder AT host +2").unwrap();
+        assert_eq!(rules[0].methods, [Method::Split(Part::with_sni_flag(1)), Method::Disorder(Part::with_host_flag(2))]);
+        // only a named position takes an offset
+        assert_eq!(error("split AT 1+2"), "line 1: expected `;`, got `+`");
+    }
+
+    #[test]
+    fn errors_name_the_line_and_the_token() {
+        assert_eq!(error("split AT 1\nIF quic THEN split AT 1"), "line 2: unknown protocol `quic`");
+        assert_eq!(error("IF tls split AT 1"), "line 1: expected then, got `split`");
+        assert_eq!(error("split 1"), "line 1: expected at, got `1`");
+        assert_eq!(error("split AT 1 disorder AT 2"), "line 1: expected `;`, got `disorder`");
+        assert_eq!(error("# comment\n\nsplice AT 1"), "line 3: unknown method `splice`");
+        assert_eq!(error("split AT sni-start"), "line 1: invalid position `sni-start`");
+        assert_eq!(error("split AT -1"), "line 1: invalid position `-1`");
+        assert_eq!(error("split AT header="), "line 1: invalid position `header=`");
+        assert_eq!(error("split AT sni+x"), "line 1: invalid offset `x`");
+    }
+
+    #[test]
+    fn errors_on_a_rule_cut_short() {
+        assert_eq!(error("IF"), "line 1: unexpected end of rule");
+        assert_eq!(error("IF tls THEN"), "line 1: unexpected end of rule");
+        assert_eq!(error("split AT"), "line 1: unexpected end of rule");
+        assert_eq!(error("split AT sni+"), "line 1: unexpected end of rule");
+        assert_eq!(error("split AT 1;;"), "line 1: unexpected end of rule");
+    }
+}
@@ -0,0 +1,375 @@
+use std::{fmt, str};
+
+pub const CONTENT_TYPE_HANDSHAKE: u8 = 0x16;
+pub const HANDSHAKE_CLIENT_HELLO: u8 = 0x01;
+pub const EXTENSION_SERVER_NAME: u16 = 0x0000;
+// DTLS 1.0 and 1.2, 1.3 has its own record format
+const DTLS_VERSIONS: [u16; 2] = [0xfeff, 0xfefd];
+
+#[derive(Debug, PartialEq)]
+pub enum TlsParseError {
+    NotTls,
+    TruncatedRecord,
+    InvalidHandshake,
+    MissingExtension,
+    InvalidSplitPosition
+}
+
+impl fmt::Display for TlsParseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TlsParseError::NotTls => f.write_str("not a tls handshake record"),
+            TlsParseError::TruncatedRecord => f.write_str("truncated tls record"),
+            TlsParseError::InvalidHandshake => f.write_str("invalid tls ClientHello"),
+            TlsParseError::MissingExtension => f.write_str("tls ClientHello without a server_name extension"),
+            TlsParseError::InvalidSplitPosition => f.write_str("split position outside the tls record payload")
+        }
+    }
+}
+
+impl std::error::Error for TlsParseError {}
+
+// fields borrow the original buffer, `offset`s are absolute positions in it
+#[derive(Debug)]
+pub struct TlsRecord<'a> {
+    pub content_type: u8,
+    pub fragment: &'a [u8]
+}
+
+#[derive(Debug)]
+pub struct TlsHandshake<'a> {
+    pub msg_type: u8,
+    pub body: &'a [u8]
+}
+
+#[derive(Debug)]
+pub struct TlsExtension<'a> {
+    pub ext_type: u16,
+    pub offset: usize,
+    pub data: &'a [u8]
+}
+
+#[derive(Debug)]
+pub struct ClientHello<'a> {
+    legacy_version: u16,
+    session_id: &'a [u8],
+    cipher_suites: &'a [u8],
+    extensions: Vec<TlsExtension<'a>>
+}
+
+impl<'a> ClientHello<'a> {
+    pub fn legacy_version(&self) -> u16 {
+        self.legacy_version
+    }
+
+    pub fn session_id(&self) -> &'a [u8] {
+        self.session_id
+    }
+
+    pub fn cipher_suites(&self) -> impl Iterator<Item = u16> + 'a {
+        self.cipher_suites.chunks_exact(2).map(|c| u16::from_be_bytes([c[0], c[1]]))
+    }
+
+    pub fn extensions(&self) -> &[TlsExtension<'a>] {
+        &self.extensions
+    }
+
+    pub fn extension(&self, ext_type: u16) -> Option<&TlsExtension<'a>> {
+        self.extensions.iter().find(|ext| ext.ext_type == ext_type)
+    }
+
+    pub fn sni(&self) -> Option<&'a str> {
+        let (_, name) = self.host_name()?;
+        str::from_utf8(name).ok()
+    }
+
+    // absolute offset of the first byte of the server name
+    pub fn sni_offset(&self) -> Option<usize> {
+        self.host_name().map(|(offset, _)| offset)
+    }
+
+    fn host_name(&self) -> Option<(usize, &'a [u8])> {
+        let ext = self.extension(EXTENSION_SERVER_NAME)?;
+        // server_name_list length (2), name_type (1), host_name length (2)
+        let mut reader = Reader::new(ext.data, ext.offset);
+        reader.u16()?;
+        if reader.u8()? != 0 {
+            return None;
+        }
+        let len = reader.u16()? as usize;
+        let offset = reader.position();
+        reader.bytes(len).map(|name| (offset, name))
+    }
+}
+
+// a ClientHello cut short inside the extension list still parses, the
+// extensions that were fully received are kept
+pub fn parse_client_hello(buf: &[u8]) -> Result<ClientHello<'_>, TlsParseError> {
+    let record = parse_record(buf)?;
+    if record.content_type != CONTENT_TYPE_HANDSHAKE {
+        return Err(TlsParseError::NotTls);
+    }
+    let handshake = parse_handshake(record.fragment)?;
+    if handshake.msg_type != HANDSHAKE_CLIENT_HELLO {
+        return Err(TlsParseError::InvalidHandshake);
+    }
+    parse_client_hello_body(handshake.body, 9, false)
+}
+
+// dtls adds an epoch and sequence number to the record header, a sequence
+// number and fragment range to the handshake header and a cookie after the
+// session id. the rest of the hello is the same as tls
+pub fn parse_dtls_client_hello(buf: &[u8]) -> Result<ClientHello<'_>, TlsParseError> {
+    let mut reader = Reader::new(buf, 0);
+    if reader.u8().ok_or(TlsParseError::NotTls)? != CONTENT_TYPE_HANDSHAKE {
+        return Err(TlsParseError::NotTls);
+    }
+    let version = reader.u16().ok_or(TlsParseError::TruncatedRecord)?;
+    if !DTLS_VERSIONS.contains(&version) {
+        return Err(TlsParseError::NotTls);
+    }
+    // epoch (2), sequence number (6)
+    reader.bytes(8).ok_or(TlsParseError::TruncatedRecord)?;
+    let len = reader.u16().ok_or(TlsParseError::TruncatedRecord)? as usize;
+    let fragment = &buf[13..buf.len().min(13 + len)];
+
+    let mut reader = Reader::new(fragment, 13);
+    if reader.u8().ok_or(TlsParseError::TruncatedRecord)? != HANDSHAKE_CLIENT_HELLO {
+        return Err(TlsParseError::InvalidHandshake);
+    }
+    // length (3), message sequence (2), fragment offset (3)
+    reader.bytes(8).ok_or(TlsParseError::TruncatedRecord)?;
+    let fragment_len = reader.bytes(3).ok_or(TlsParseError::TruncatedRecord)?;
+    let fragment_len = u32::from_be_bytes([0, fragment_len[0], fragment_len[1], fragment_len[2]]) as usize;
+    let body = &fragment[12..fragment.len().min(12 + fragment_len)];
+    parse_client_hello_body(body, 25, true)
+}
+
+// `base` is the offset of the body in the hello buffer
+fn parse_client_hello_body(body: &[u8], base: usize, dtls: bool) -> Result<ClientHello<'_>, TlsParseError> {
+    let mut reader = Reader::new(body, base);
+    let legacy_version = reader.u16().ok_or(TlsParseError::TruncatedRecord)?;
+    reader.bytes(32).ok_or(TlsParseError::TruncatedRecord)?;
+    let session_id_len = reader.u8().ok_or(TlsParseError::TruncatedRecord)? as usize;
+    if session_id_len > 32 {
+        return Err(TlsParseError::InvalidHandshake);
+    }
+    let session_id = reader.bytes(session_id_len).ok_or(TlsParseError::TruncatedRecord)?;
+    if dtls {
+        let cookie_len = reader.u8().ok_or(TlsParseError::TruncatedRecord)? as usize;
+        reader.bytes(cookie_len).ok_or(TlsParseError::TruncatedRecord)?;
+    }
+    let cipher_suites_len = reader.u16().ok_or(TlsParseError::TruncatedRecord)? as usize;
+    if !cipher_suites_len.is_multiple_of(2) {
+        return Err(TlsParseError::InvalidHandshake);
+    }
+    let cipher_suites = reader.bytes(cipher_suites_len).ok_or(TlsParseError::TruncatedRecord)?;
+    let compression_len = reader.u8().ok_or(TlsParseError::TruncatedRecord)? as usize;
+    reader.bytes(compression_len).ok_or(TlsParseError::TruncatedRecord)?;
+
+    let mut extensions = Vec::new();
+    // the extension list is optional
+    if let Some(extensions_len) = reader.u16() {
+        let available = (extensions_len as usize).min(reader.remaining());
+        let base = reader.position();
+        let mut ext_reader = Reader::new(reader.bytes(available).unwrap_or_default(), base);
+        while let Some(ext) = parse_extension(&mut ext_reader) {
+            extensions.push(ext);
+        }
+    }
+
+    Ok(ClientHello {
+        legacy_version,
+        session_id,
+        cipher_suites,
+        extensions
+    })
+}
+
+fn parse_record(buf: &[u8]) -> Result<TlsRecord<'_>, TlsParseError> {
+    let mut reader = Reader::new(buf, 0);
+    let content_type = reader.u8().ok_or(TlsParseError::NotTls)?;
+    let version = reader.u16().ok_or(TlsParseError::TruncatedRecord)?;
+    if version >> 8 != 0x03 {
+        return Err(TlsParseError::NotTls);
+    }
+    let len = reader.u16().ok_or(TlsParseError::TruncatedRecord)? as usize;
+    let fragment = &buf[5..buf.len().min(5 + len)];
+    Ok(TlsRecord { content_type, fragment })
+}
+
+fn parse_handshake(fragment: &[u8]) -> Result<TlsHandshake<'_>, TlsParseError> {
+    let mut reader = Reader::new(fragment, 5);
+    let msg_type = reader.u8().ok_or(TlsParseError::TruncatedRecord)?;
+    let len = reader.bytes(3).ok_or(TlsParseError::TruncatedRecord)?;
+    let len = u32::from_be_bytes([0, len[0], len[1], len[2]]) as usize;
+    let body = &fragment[4..fragment.len().min(4 + len)];
+    Ok(TlsHandshake { msg_type, body })
+}
+
+fn parse_extension<'a>(reader: &mut Reader<'a>) -> Option<TlsExtension<'a>> {
+    let ext_type = reader.u16()?;
+    let len = reader.u16()? as usize;
+    let offset = reader.position();
+    let data = reader.bytes(len)?;
+    Some(TlsExtension { ext_type, offset, data })
+}
+
+// cursor over a slice that also tracks the absolute position in the hello buffer
+struct Reader<'a> {
+    buf: &'a [u8],
+    pos: usize,
+    base: usize
+}
+
+impl<'a> Reader<'a> {
+    fn new(buf: &'a [u8], base: usize) -> Self {
+        Reader { buf, pos: 0, base }
+    }
+
+    fn position(&self) -> usize {
+        self.base + self.pos
+    }
+
+    fn remaining(&self) -> usize {
+        self.buf.len() - self.pos
+    }
+
+    fn bytes(&mut self, len: usize) -> Option<&'a [u8]> {
+        let bytes = self.buf.get(self.pos..self.pos + len)?;
+        self.pos += len;
+        Some(bytes)
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        self.bytes(1).map(|b| b[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        self.bytes(2).map(|b| u16::from_be_bytes([b[0], b[1]]))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::benchmark::synthetic_client_hello;
+    use rand::{rngs::StdRng, Rng, SeedableRng};
+
+    // the synthetic hello as dtls 1.2, an empty cookie after the session id
+    fn dtls_client_hello(sni: &str) -> Vec<u8> {
+        let tls = synthetic_client_hello(sni, 0);
+        let mut body = tls[9..].to_vec();
+        body[..2].copy_from_slice(&[0xfe, 0xfd]);
+        body.insert(2 + 32 + 1 + 32, 0);
+        let len = (body.len() as u32).to_be_bytes();
+
+        let mut handshake = vec![HANDSHAKE_CLIENT_HELLO, len[1], len[2], len[3], 0, 0, 0, 0, 0, len[1], len[2], len[3]];
+        handshake.append(&mut body);
+        let mut record = vec![CONTENT_TYPE_HANDSHAKE, 0xfe, 0xfd, 0, 0, 0, 0, 0, 0, 0, 0];
+        record.extend_from_slice(&(handshake.len() as u16).to_be_bytes());
+        record.append(&mut handshake);
+        record
+    }
+
+    #[test]
+    fn parses_a_client_hello() {
+        let buf = synthetic_client_hello("example.com", 0);
+        let hello = parse_client_hello(&buf).unwrap();
+        assert_eq!(hello.legacy_version(), 0x0303);
+        assert_eq!(hello.session_id(), [0xa5; 32]);
+        assert_eq!(hello.cipher_suites().collect::<Vec<_>>(), [0x1301]);
+        assert_eq!(hello.sni(), Some("example.com"));
+        let offset = hello.sni_offset().unwrap();
+        assert_eq!(&buf[offset..offset + 11], b"example.com");
+    }
+
+    #[test]
+    fn rejects_other_records_and_messages() {
+        assert_eq!(parse_client_hello(b"GET / HTTP/1.1\r\n\r\n").unwrap_err(), TlsParseError::NotTls);
+        assert_eq!(parse_client_hello(&[]).unwrap_err(), TlsParseError::NotTls);
+        assert_eq!(parse_client_hello(&[0x16, 0x03]).unwrap_err(), TlsParseError::TruncatedRecord);
+
+        let mut buf = synthetic_client_hello("example.com", 0);
+        buf[0] = 0x17;
+        assert_eq!(parse_client_hello(&buf).unwrap_err(), TlsParseError::NotTls);
+        buf[0] = CONTENT_TYPE_HANDSHAKE;
+        buf[5] = 0x02;
+        assert_eq!(parse_client_hello(&buf).unwrap_err(), TlsParseError::InvalidHandshake);
+    }
+
+    #[test]
+    fn truncated_extensions_keep_the_complete_ones() {
+        let buf = synthetic_client_hello("example.com", 512);
+        let hello = parse_client_hello(&buf).unwrap();
+        assert_eq!(hello.extensions().len(), 2);
+
+        // cut inside the padding extension after the server name
+        let hello = parse_client_hello(&buf[..buf.len() - 100]).unwrap();
+        assert_eq!(hello.extensions().len(), 1);
+        assert_eq!(hello.sni(), Some("example.com"));
+
+        // cut inside the server name
+        let offset = hello.sni_offset().unwrap();
+        let hello = parse_client_hello(&buf[..offset + 4]).unwrap();
+        assert!(hello.extensions().is_empty());
+        assert_eq!(hello.sni(), None);
+    }
+
+    #[test]
+    fn truncated_before_the_extensions() {
+        let buf = synthetic_client_hello("example.com", 0);
+        // up to the middle of the random
+        assert_eq!(parse_client_hello(&buf[..20]).unwrap_err(), TlsParseError::TruncatedRecord);
+    }
+
+    #[test]
+    fn parses_a_dtls_client_hello() {
+        let buf = dtls_client_hello("example.com");
+        let hello = parse_dtls_client_hello(&buf).unwrap();
+        assert_eq!(hello.legacy_version(), 0xfefd);
+        assert_eq!(hello.sni(), Some("example.com"));
+        let offset = hello.sni_offset().unwrap();
+        assert_eq!(&buf[offset..offset + 11], b"example.com");
+        // the tls parser doesn't take a dtls record and the other way around
+        assert_eq!(parse_client_hello(&buf).unwrap_err(), TlsParseError::NotTls);
+        let tls = synthetic_client_hello("example.com", 0);
+        assert_eq!(parse_dtls_client_hello(&tls).unwrap_err(), TlsParseError::NotTls);
+    }
+
+    #[test]
+    fn truncated_dtls_client_hello() {
+        let buf = dtls_client_hello("example.com");
+        assert_eq!(parse_dtls_client_hello(&buf[..10]).unwrap_err(), TlsParseError::TruncatedRecord);
+        assert_eq!(parse_dtls_client_hello(&buf[..30]).unwrap_err(), TlsParseError::TruncatedRecord);
+        let hello = parse_dtls_client_hello(&buf[..buf.len() - 3]).unwrap();
+        assert_eq!(hello.sni(), None);
+    }
+
+    // random byte flips and cuts must never panic, and a server name that
+    // is found must be where the parser says it is
+    #[test]
+    fn fuzz_mutated_client_hellos() {
+        let mut rng = StdRng::seed_from_u64(0x5eed);
+        let tls = synthetic_client_hello("example.com", 300);
+        let dtls = dtls_client_hello("example.com");
+        for i in 0..10_000 {
+            let mut buf = if i % 2 == 0 { tls.clone() } else { dtls.clone() };
+            for _ in 0..rng.gen_range(1..=8) {
+                let at = rng.gen_range(0..buf.len());
+                buf[at] = rng.gen();
+            }
+            if rng.gen_bool(0.3) {
+                buf.truncate(rng.gen_range(0..buf.len()));
+            }
+            for hello in [parse_client_hello(&buf), parse_dtls_client_hello(&buf)].into_iter().flatten() {
+                if let (Some(sni), Some(offset)) = (hello.sni(), hello.sni_offset()) {
+                    assert_eq!(&buf[offset..offset + sni.len()], sni.as_bytes());
+                }
+                for ext in hello.extensions() {
+                    assert_eq!(&buf[ext.offset..ext.offset + ext.data.len()], ext.data);
+                }
+            }
+        }
+    }
+}
@@ -0,0 +1,20 @@
+use std::{io, time::Duration};
+use tokio::{net::TcpStream, time::Instant};
+
+// time of a plain tcp handshake, i.e. one round trip to the target
+pub async fn test_connectivity(target: &str, port: u16) -> Result<Duration, io::Error> {
+    let start = Instant::now();
+    TcpStream::connect((target, port)).await?;
+    Ok(start.elapsed())
+}
+
+// `host:port` or `[v6]:port`
+pub fn parse_host_port(s: &str) -> Result<(String, u16), String> {
+    let (host, port) = s.rsplit_once(':').ok_or_else(|| format!("expected <host>:<port>, got `{s}`"))?;
+    let host = host.strip_prefix('[').and_then(|h| h.strip_suffix(']')).unwrap_or(host);
+    let port = port.parse().map_err(|_| format!("invalid port `{port}`"))?;
+    if host.is_empty() {
+        return Err(format!("expected <host>:<port>, got `{s}`"));
+    }
+    Ok((host.to_owned(), port))
+}
@@ -0,0 +1,166 @@
+use crate::{metrics::ConnectionId, sink::{DesyncSink, TtlControl}, Method};
+use serde::{Serialize, Serializer};
+use std::{
+    cell::{Cell, RefCell},
+    fs::{File, OpenOptions},
+    io::{Result, Write},
+    net::SocketAddr,
+    path::Path,
+    pin::Pin,
+    sync::Mutex,
+    task::{Context, Poll},
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::io::AsyncWrite;
+use tracing::warn;
+
+// the bytes desync handed to the upstream socket, one segment per write
+// between flushes, method or ttl changes
+#[derive(Debug, Default, Serialize)]
+pub struct WireLog {
+    pub segments: Vec<WireSegment>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct WireSegment {
+    #[serde(serialize_with = "as_hex")]
+    pub bytes: Vec<u8>,
+    // None for the bytes after the last split
+    pub method: Option<String>,
+    pub ttl_override: Option<u8>,
+}
+
+fn as_hex<S: Serializer>(bytes: &[u8], serializer: S) -> std::result::Result<S::Ok, S::Error> {
+    serializer.collect_str(&bytes.iter().map(|b| format!("{b:02x}")).collect::<String>())
+}
+
+// `--wire-log-file`, one json line per connection
+pub struct WireLogFile(Mutex<File>);
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    connection: ConnectionId,
+    timestamp_ms: u128,
+    #[serde(flatten)]
+    log: &'a WireLog,
+}
+
+impl WireLogFile {
+    pub fn open(path: &Path) -> Result<Self> {
+        let file = OpenOptions::new().create(true).append(true).open(path)?;
+        Ok(WireLogFile(Mutex::new(file)))
+    }
+
+    pub fn append(&self, connection: ConnectionId, log: &WireLog) {
+        let timestamp_ms = SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_millis();
+        let entry = Entry { connection, timestamp_ms, log };
+        let mut line = serde_json::to_vec(&entry).expect("a wire log always serializes");
+        line.push(b'\n');
+        // one write per line keeps concurrent connections from interleaving
+        let mut file = self.0.lock().unwrap_or_else(|err| err.into_inner());
+        if let Err(err) = file.write_all(&line) {
+            warn!("wire log: {err}");
+        }
+    }
+}
+
+// passes everything through to the upstream socket and keeps a copy of
+// what was written
+pub struct WireRecorder<'a, S> {
+    inner: &'a mut S,
+    log: RefCell<WireLog>,
+    method: Option<String>,
+    default_ttl: Option<u32>,
+    ttl_override: Cell<Option<u8>>,
+    // the next write starts a new segment
+    boundary: Cell<bool>,
+}
+
+impl<'a, S: DesyncSink> WireRecorder<'a, S> {
+    pub fn new(inner: &'a mut S) -> Self {
+        let default_ttl = inner.ttl().ok();
+        WireRecorder {
+            inner,
+            log: RefCell::default(),
+            method: None,
+            default_ttl,
+            ttl_override: Cell::new(None),
+            boundary: Cell::new(true),
+        }
+    }
+
+    pub fn into_log(self) -> WireLog {
+        self.log.into_inner()
+    }
+
+    fn record(&self, bytes: &[u8]) {
+        let mut log = self.log.borrow_mut();
+        match log.segments.last_mut() {
+            Some(last) if !self.boundary.get() => last.bytes.extend_from_slice(bytes),
+            _ => log.segments.push(WireSegment {
+                bytes: bytes.to_vec(),
+                method: self.method.clone(),
+                ttl_override: self.ttl_override.get(),
+            }),
+        }
+        self.boundary.set(false);
+    }
+}
+
+impl<S: DesyncSink> TtlControl for WireRecorder<'_, S> {
+    fn ttl(&self) -> Result<u32> {
+        self.inner.ttl()
+    }
+
+    fn set_ttl(&self, ttl: u32) -> Result<()> {
+        self.inner.set_ttl(ttl)?;
+        self.ttl_override.set((Some(ttl) != self.default_ttl).then_some(ttl as u8));
+        self.boundary.set(true);
+        Ok(())
+    }
+}
+
+impl<S: DesyncSink> DesyncSink for WireRecorder<'_, S> {
+    fn send_oob(&self, data: &[u8]) -> Result<usize> {
+        let sent = self.inner.send_oob(data)?;
+        // an urgent segment never merges with its neighbours
+        self.boundary.set(true);
+        self.record(&data[..sent]);
+        self.boundary.set(true);
+        Ok(sent)
+    }
+
+    fn set_cork(&self, cork: bool) -> Result<()> {
+        self.inner.set_cork(cork)
+    }
+
+    fn peer_addr(&self) -> Result<SocketAddr> {
+        self.inner.peer_addr()
+    }
+
+    fn begin_method(&mut self, method: Option<&Method>) {
+        self.method = method.map(Method::to_string);
+        self.boundary.set(true);
+    }
+}
+
+impl<S: DesyncSink> AsyncWrite for WireRecorder<'_, S> {
+    fn poll_write(self: Pin<&mut Self>, cx: &mut Context<'_>, buf: &[u8]) -> Poll<Result<usize>> {
+        let this = self.get_mut();
+        let poll = Pin::new(&mut *this.inner).poll_write(cx, buf);
+        if let Poll::Ready(Ok(written)) = poll {
+            this.record(&buf[..written]);
+        }
+        poll
+    }
+
+    fn poll_flush(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        let this = self.get_mut();
+        this.boundary.set(true);
+        Pin::new(&mut *this.inner).poll_flush(cx)
+    }
+
+    fn poll_shutdown(self: Pin<&mut Self>, cx: &mut Context<'_>) -> Poll<Result<()>> {
+        Pin::new(&mut *self.get_mut().inner).poll_shutdown(cx)
+    }
+}